@@ -0,0 +1,106 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    Json, response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::api::ApiState;
+use crate::api::client::{DataView, PutBody, etag, refuse_crdt, replicate_write, too_large};
+use crate::api::crdt::IncrBody;
+use crate::store::{AtomicOp, AtomicOutcome};
+use crate::util::{Operation, now_ms};
+
+#[derive(Serialize)]
+pub struct AtomicResp {
+    #[serde(flatten)]
+    data: DataView, // the value written
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+    previous: Option<DataView>, // the live value it replaced, null if there was none
+}
+
+// adds `by` to a value holding a decimal integer; a missing key starts from 0
+pub async fn atomic_incr(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(body): Json<IncrBody>,
+) -> Response {
+    run_atomic(&state, key, AtomicOp::Incr(body.by), None, "/key/:key/incr").await
+}
+
+pub async fn atomic_append(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(body): Json<PutBody>,
+) -> Response {
+    let expires_at = body.ttl_ms.map(|ttl| now_ms() + ttl);
+    run_atomic(&state, key, AtomicOp::Append(body.value.into_bytes()), expires_at, "/key/:key/append").await
+}
+
+// a put that also returns the value it replaced
+pub async fn atomic_getset(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(body): Json<PutBody>,
+) -> Response {
+    let expires_at = body.ttl_ms.map(|ttl| now_ms() + ttl);
+    run_atomic(&state, key, AtomicOp::GetSet(body.value.into_bytes()), expires_at, "/key/:key/getset").await
+}
+
+// Atomic ops read the current value, so like conditional writes only the leader's WAL writer
+// runs them. The result is logged and replicated as an ordinary put. 409 if the op doesn't
+// apply to the value there, 413 if the result would be over --max-value-bytes.
+async fn run_atomic(state: &ApiState, key: String, op: AtomicOp, expires_at: Option<u64>, route: &str) -> Response {
+    if let Some(resp) = refuse_crdt(state, &key, "POST", route) {
+        return resp;
+    }
+    let cluster = state.cluster.read().await;
+    let node_id = cluster.node_id;
+    let leader_id = cluster.leader_id;
+    let peers = cluster.peer_addresses.clone();
+    drop(cluster);
+    if node_id != leader_id {
+        state.metrics.requests.with_label_values(&["POST", route, "421"]).inc();
+        let msg = format!("atomic operations must be sent to the leader (node {})", leader_id);
+        return (StatusCode::MISDIRECTED_REQUEST, msg).into_response();
+    }
+
+    let (entry, previous) = match state.wal_writer.submit_atomic(key.clone(), op, node_id, expires_at, state.max_value_bytes).await {
+        Ok(AtomicOutcome::Applied { entry, previous }) => (entry, previous),
+        Ok(AtomicOutcome::TooLarge(len)) => {
+            let msg = format!("key {}: the result would be {} bytes, over the {} byte value limit", key, len, state.max_value_bytes);
+            return too_large(state, "POST", route, msg);
+        }
+        Ok(AtomicOutcome::Rejected(reason)) => {
+            state.metrics.errors.with_label_values(&["atomic_rejected"]).inc();
+            state.metrics.requests.with_label_values(&["POST", route, "409"]).inc();
+            return (StatusCode::CONFLICT, format!("key {}: {}", key, reason)).into_response();
+        }
+        Err(e) => {
+            eprintln!("POST {} failed: {}", key, e);
+            state.metrics.errors.with_label_values(&["wal"]).inc();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if let Err(resp) = replicate_write(state, &entry, peers).await {
+        return resp;
+    }
+
+    let Operation::Put { value, content_type, .. } = entry.operation else {
+        unreachable!("atomic ops are logged as puts");
+    };
+    let resp = AtomicResp {
+        data: DataView::new(Some(value), content_type),
+        ts: entry.ts,
+        node_id: entry.node_id,
+        written_at: state.clock.wall_ms(entry.ts),
+        previous: previous.map(|v| DataView::new(v.data, v.content_type)),
+    };
+    let op = route.trim_start_matches("/key/:key/");
+    state.metrics.kv_ops.with_label_values(&[op]).inc();
+    state.metrics.requests.with_label_values(&["POST", route, "200"]).inc();
+    (StatusCode::OK, [(header::ETAG, etag(entry.ts, entry.node_id))], Json(resp)).into_response()
+}
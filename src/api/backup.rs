@@ -0,0 +1,128 @@
+use std::{convert::Infallible, io::{self, Write}};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, StatusCode},
+    Json, response::{IntoResponse, Response},
+};
+use futures::stream;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::api::ApiState;
+use crate::store::{Snapshot, install_snapshot};
+
+// A backup archive is a snapshot file's JSON: the whole keyspace, tombstones and retained
+// versions included, and the clock's high-water mark, captured at one WAL position.
+
+const CHUNK_BYTES: usize = 64 * 1024;
+
+// hands serialized JSON to the response body in chunks as it is produced
+struct ChunkWriter {
+    tx: mpsc::Sender<Bytes>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        // the client hung up; stop serializing
+        self.tx.blocking_send(chunk).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+// Streams a consistent copy of the store while the node keeps serving. The keyspace and the
+// clock are captured together under the WAL lock, like a checkpoint; serializing happens
+// after it is released.
+pub async fn admin_backup(State(state): State<ApiState>) -> Response {
+    let snapshot = {
+        let wal = state.wal.lock().await;
+        match state.store.dump() {
+            Ok(data) => Snapshot { lamport_hw: state.clock.tick_now(), wal_position: wal.position(), data },
+            Err(e) => {
+                eprintln!("Backup failed: {}", e);
+                state.metrics.errors.with_label_values(&["backup"]).inc();
+                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+            }
+        }
+    };
+    let filename = format!("backup-{:020}.json", snapshot.lamport_hw);
+
+    let (tx, rx) = mpsc::channel::<Bytes>(16);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter { tx, buf: Vec::with_capacity(CHUNK_BYTES) };
+        if let Err(e) = serde_json::to_writer(&mut writer, &snapshot).map_err(io::Error::from).and_then(|_| writer.flush()) {
+            eprintln!("Backup stream ended early: {}", e);
+        }
+    });
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (Ok::<_, Infallible>(chunk), rx))
+    });
+
+    state.metrics.requests.with_label_values(&["GET", "/admin/backup", "200"]).inc();
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(chunks),
+    ).into_response()
+}
+
+#[derive(Serialize)]
+pub struct RestoreResp {
+    keys: usize,
+    lamport_hw: u64,
+}
+
+// Loads a backup archive into a node that holds no data yet. The restored state is
+// checkpointed before it is served, so a restart finds it; see store::install_snapshot. The
+// node can then rejoin replication like any other; writes made elsewhere since the backup
+// aren't in it.
+pub async fn admin_restore(State(state): State<ApiState>, body: Bytes) -> Response {
+    const ROUTE: &str = "/admin/restore";
+    let archive = match serde_json::from_slice::<Snapshot>(&body) {
+        Ok(archive) => archive,
+        Err(e) => {
+            state.metrics.requests.with_label_values(&["POST", ROUTE, "400"]).inc();
+            return (StatusCode::BAD_REQUEST, format!("not a backup archive: {}", e)).into_response();
+        }
+    };
+    let (keys, lamport_hw) = (archive.data.len(), archive.lamport_hw);
+
+    // no writes land while the node is checked and loaded
+    let wal = state.wal.lock().await;
+    let empty = match state.store.dump() {
+        Ok(current) => current.is_empty(),
+        Err(e) => {
+            eprintln!("Restore failed: {}", e);
+            state.metrics.errors.with_label_values(&["restore"]).inc();
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    if !empty {
+        state.metrics.requests.with_label_values(&["POST", ROUTE, "409"]).inc();
+        return (StatusCode::CONFLICT, "node already holds data; restore into a fresh node").into_response();
+    }
+    if let Err(e) = install_snapshot(&state.store, &state.clock, &wal, &state.snapshot_cfg, archive).await {
+        eprintln!("Restore failed: {}", e);
+        state.metrics.errors.with_label_values(&["restore"]).inc();
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    drop(wal);
+
+    state.metrics.requests.with_label_values(&["POST", ROUTE, "200"]).inc();
+    (StatusCode::OK, Json(RestoreResp { keys, lamport_hw })).into_response()
+}
@@ -1,103 +1,424 @@
-use std::{collections::HashMap, sync::Arc, time::{SystemTime, UNIX_EPOCH, Duration}};
 use prometheus::{Encoder, TextEncoder};
 use axum::{
-    extract::{Path, State},
-    routing::{get, post, put, delete},
+    body::Bytes,
+    extract::{DefaultBodyLimit, MatchedPath, Path, Query, Request, State},
+    middleware::{self, Next},
+    routing::{get, post, put},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
     Json, Router, response::{IntoResponse, Response},
 };
+use std::collections::HashSet;
+use base64::{Engine, engine::general_purpose::STANDARD};
 use serde::{Deserialize, Serialize};
-use crate::api::{ApiState, Metrics};
-use crate::store::{LamportClock, Value};
-use crate::util::{LogEntry, Operation};
-use crate::replication::ReplicateBody;
+use crate::api::ApiState;
+use crate::api::atomic::{atomic_incr, atomic_append, atomic_getset};
+use crate::api::backup::{admin_backup, admin_restore};
+use crate::api::crdt::{counter_incr, counter_get, counter_delete, set_add, set_remove, set_get, set_delete};
+use crate::api::gc::{ConfirmBody, ConfirmResp};
+use crate::api::txn::{begin_txn, txn_get, txn_put, txn_delete, txn_commit, txn_abort};
+use crate::api::watch::watch;
+use crate::store::{Value, AsOf, ReadAt, LamportClock, Condition, CondOutcome, checkpoint, list_segments};
+use crate::util::{LogEntry, Operation, VersionVector, MAX_WRITE_BYTES, now_ms};
+use crate::replication::{ReplicateBody, REPLICATE_BODY_LIMIT};
 use crate::cluster::{quorum_write, quorum_read};
 
+// what a client write may send: a value or batch of MAX_WRITE_BYTES as JSON text, with room
+// for keys and escaping; longer values are refused against --max-value-bytes before that
+const WRITE_BODY_LIMIT: usize = MAX_WRITE_BYTES + 1024 * 1024;
+
 pub struct RouterBuilder;
 
 impl RouterBuilder {
     pub fn with_state(state: ApiState) -> Router {
         Router::new()
             .route("/key/:key", put(put_key).get(get_key).delete(delete_key))
-            .route("/replicate", post(replicate))
+            .route("/key/:key/history", get(key_history))
+            .route("/key/:key/incr", post(atomic_incr))
+            .route("/key/:key/append", post(atomic_append))
+            .route("/key/:key/getset", post(atomic_getset))
+            .route("/raw/:key", put(put_raw).get(get_raw).delete(delete_key))
+            .route("/keys", get(list_keys))
+            .route("/index/:name", get(index_query))
+            .route("/batch", post(batch))
+            .route("/watch", get(watch))
+            .route("/counter/:key", get(counter_get).delete(counter_delete))
+            .route("/counter/:key/incr", post(counter_incr))
+            .route("/set/:key", get(set_get).delete(set_delete))
+            .route("/set/:key/add", post(set_add))
+            .route("/set/:key/remove", post(set_remove))
+            .route("/txn", post(begin_txn))
+            .route("/txn/:id/key/:key", put(txn_put).get(txn_get).delete(txn_delete))
+            .route("/txn/:id/commit", post(txn_commit))
+            .route("/txn/:id/abort", post(txn_abort))
+            .route("/replicate", post(replicate).layer(DefaultBodyLimit::max(REPLICATE_BODY_LIMIT)))
+            .route("/gc/confirm", post(gc_confirm))
             .route("/ping", get(ping))
             .route("/health", get(health))
             .route("/metrics", get(metrics))
+            .route("/admin/compact", post(admin_compact))
+            .route("/admin/wal/segments", get(admin_wal_segments))
+            .route("/admin/backup", get(admin_backup))
+            // archives are as big as the keyspace
+            .route("/admin/restore", post(admin_restore).layer(DefaultBodyLimit::disable()))
+            .route_layer(middleware::from_fn_with_state(state.clone(), refuse_when_read_only))
+            .layer(DefaultBodyLimit::max(WRITE_BODY_LIMIT))
             .with_state(state)
     }
 }
 
+// A node serving a point-in-time recovery answers reads only. Anything that writes, and /watch,
+// whose history would run past the recovered point, gets 503.
+async fn refuse_when_read_only(State(state): State<ApiState>, req: Request, next: Next) -> Response {
+    let Some(ts) = state.read_only_at else {
+        return next.run(req).await;
+    };
+    let route = req.extensions().get::<MatchedPath>().map(|p| p.as_str().to_string()).unwrap_or_default();
+    if matches!(*req.method(), Method::GET | Method::HEAD) && route != "/watch" {
+        return next.run(req).await;
+    }
+    state.metrics.errors.with_label_values(&["read_only"]).inc();
+    state.metrics.requests.with_label_values(&[req.method().as_str(), &route, "503"]).inc();
+    (StatusCode::SERVICE_UNAVAILABLE, format!("read-only: recovered to ts {}", ts)).into_response()
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PutBody {
     pub value: String,
+    // the key reads as deleted this long after the write
+    #[serde(default)]
+    pub ttl_ms: Option<u64>,
 }
 
 
 async fn put_key(
     State(state): State<ApiState>,
     Path(key): Path<String>,
+    headers: HeaderMap,
     Json(body): Json<PutBody>,
 ) -> Response {
-    let ts = state.clock.tick_send();
-    let node_id = state.cluster.read().await.node_id;
-    let log_entry = LogEntry {
-        ts: ts,
-        node_id: node_id,
-        operation: Operation::Put { key: key.clone(), value: body.value.clone() },
+    put_value(&state, key, &headers, body.value.into_bytes(), None, body.ttl_ms, "/key/:key").await
+}
+
+#[derive(Deserialize)]
+pub struct RawQuery {
+    ttl_ms: Option<u64>,
+}
+
+// stores the request body as is, along with its Content-Type
+async fn put_raw(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Query(q): Query<RawQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    put_value(&state, key, &headers, body.to_vec(), content_type, q.ttl_ms, "/raw/:key").await
+}
+
+async fn put_value(
+    state: &ApiState,
+    key: String,
+    headers: &HeaderMap,
+    value: Vec<u8>,
+    content_type: Option<String>,
+    ttl_ms: Option<u64>,
+    route: &str,
+) -> Response {
+    let Some(conditions) = conditions_from(headers) else {
+        return (StatusCode::BAD_REQUEST, "malformed If-Match/If-None-Match").into_response();
     };
+    let Some(context) = context_from(state, headers) else {
+        return (StatusCode::BAD_REQUEST, "malformed X-Context").into_response();
+    };
+    if let Some(resp) = refuse_crdt(state, &key, "PUT", route) {
+        return resp;
+    }
+    let operation = Operation::Put {
+        key: key.clone(),
+        value,
+        content_type,
+        expires_at: ttl_ms.map(|ttl| now_ms() + ttl),
+        context,
+    };
+
+    let checks = conditions.into_iter().map(|c| (key.clone(), c)).collect();
+    let entry = match commit_write(state, operation, checks, "PUT", route).await {
+        Ok(CondOutcome::Applied { entry, .. }) => entry,
+        Ok(CondOutcome::Failed { conflicts }) => return precondition_failed(state, "PUT", route, conflicts),
+        Err(resp) => return resp,
+    };
+
+    state.metrics.requests.with_label_values(&["PUT", route, "200"]).inc();
+    state.metrics.kv_ops.with_label_values(&["put"]).inc();
+
+    let mut resp = (StatusCode::OK, [(header::ETAG, etag(entry.ts, entry.node_id))]).into_response();
+    if let Operation::Put { context: Some(context), .. } = &entry.operation {
+        // what a follow-up write must carry to replace this one
+        let mut clock = context.clone();
+        clock.insert(entry.node_id, entry.ts);
+        resp.headers_mut().insert(CONTEXT_HEADER, format_context(&clock).parse().unwrap());
+    }
+    resp
+}
+
+// ETags are the value's version: "<ts>-<node_id>"
+pub(crate) fn etag(ts: u64, node_id: u64) -> String {
+    format!("\"{}-{}\"", ts, node_id)
+}
+
+// a comma-separated list of ETags, weak ones included; None if any is malformed
+fn parse_etags(raw: &str) -> Option<Vec<(u64, u64)>> {
+    raw.split(',')
+        .map(|tag| {
+            let tag = tag.trim();
+            let tag = tag.strip_prefix("W/").unwrap_or(tag);
+            let (ts, node_id) = tag.strip_prefix('"')?.strip_suffix('"')?.split_once('-')?;
+            Some((ts.parse().ok()?, node_id.parse().ok()?))
+        })
+        .collect()
+}
+
+// Causal context of a read, handed back with a write so it replaces what was read instead of
+// becoming a sibling; only used with --conflicts siblings. Formatted as "node:ts,node:ts".
+const CONTEXT_HEADER: &str = "x-context";
+
+fn format_context(context: &VersionVector) -> String {
+    context.iter().map(|(node, ts)| format!("{}:{}", node, ts)).collect::<Vec<_>>().join(",")
+}
 
-    {
-        let mut wal = state.wal.lock().await;
+fn parse_context(raw: &str) -> Option<VersionVector> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (node, ts) = part.split_once(':')?;
+            Some((node.trim().parse().ok()?, ts.trim().parse().ok()?))
+        })
+        .collect()
+}
 
-        // tests chaos injection
-        wal.append(&log_entry).unwrap();
-        if state.chaos_before_sync_ms > 0 {
-            tokio::time::sleep(Duration::from_millis(state.chaos_before_sync_ms)).await;
+// the context a write carries: None outside siblings mode, empty when the client sent none
+// (a blind write, which becomes a sibling of whatever is there); outer None if malformed
+fn context_from(state: &ApiState, headers: &HeaderMap) -> Option<Option<VersionVector>> {
+    if !state.store.keeps_siblings() {
+        return Some(None);
+    }
+    match headers.get(CONTEXT_HEADER) {
+        Some(raw) => parse_context(raw.to_str().ok()?).map(Some),
+        None => Some(Some(VersionVector::new())),
+    }
+}
+
+// conditions from If-Match / If-None-Match; None if a header can't be parsed
+fn conditions_from(headers: &HeaderMap) -> Option<Vec<Condition>> {
+    let mut conditions = Vec::new();
+    if let Some(raw) = headers.get(header::IF_MATCH) {
+        let raw = raw.to_str().ok()?.trim();
+        conditions.push(if raw == "*" { Condition::Exists } else { Condition::VersionIn(parse_etags(raw)?) });
+    }
+    if let Some(raw) = headers.get(header::IF_NONE_MATCH) {
+        let raw = raw.to_str().ok()?.trim();
+        conditions.push(if raw == "*" { Condition::Absent } else { Condition::VersionNotIn(parse_etags(raw)?) });
+    }
+    Some(conditions)
+}
+
+// 409 if the key holds a counter or set, deleted or not, which puts and deletes would not change
+pub(crate) fn refuse_crdt(state: &ApiState, key: &str, method: &str, route: &str) -> Option<Response> {
+    match state.store.get(key) {
+        Ok(Some(Value { crdt: Some(crdt), data, .. })) => {
+            state.metrics.errors.with_label_values(&["wrong_type"]).inc();
+            state.metrics.requests.with_label_values(&[method, route, "409"]).inc();
+            let msg = match data {
+                Some(_) => format!("key {} holds a {}; use /{}/:key", key, crdt.kind(), crdt.kind()),
+                None => format!("key {} is a deleted {} until its tombstone is collected", key, crdt.kind()),
+            };
+            Some((StatusCode::CONFLICT, msg).into_response())
+        }
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("{} {} failed: {}", method, key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            Some(StatusCode::INTERNAL_SERVER_ERROR.into_response())
         }
-        wal.sync().unwrap();
     }
+}
 
-    let store = state.store;
-    store.put(key, body.value, ts, node_id).await;
+// Why the write is too large to take: a value over --max-value-bytes, or more key and value
+// bytes than fit one logged write.
+pub(crate) fn oversized(state: &ApiState, operation: &Operation) -> Option<String> {
+    let puts = match operation {
+        Operation::Batch { ops } => ops.iter().collect(),
+        op => vec![op],
+    };
+    let longest = puts
+        .into_iter()
+        .filter_map(|op| match op {
+            Operation::Put { value, .. } => Some(value.len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    if longest > state.max_value_bytes {
+        return Some(format!("value of {} bytes is over the {} byte limit", longest, state.max_value_bytes));
+    }
+    let size = operation.size();
+    (size > MAX_WRITE_BYTES).then(|| format!("write of {} bytes of keys and values is over the {} byte limit", size, MAX_WRITE_BYTES))
+}
 
+pub(crate) fn too_large(state: &ApiState, method: &str, route: &str, msg: String) -> Response {
+    state.metrics.errors.with_label_values(&["too_large"]).inc();
+    state.metrics.requests.with_label_values(&[method, route, "413"]).inc();
+    (StatusCode::PAYLOAD_TOO_LARGE, msg).into_response()
+}
+
+// Logs the write, applies it and, on the leader, replicates it to a quorum. Writes with
+// (key, condition) checks are only taken by the leader, whose WAL writer evaluates them and
+// assigns the timestamp; if one fails nothing is written and the conflicts are returned.
+// With siblings every write goes that way, so contexts read from the leader cover exactly
+// the writes it had applied.
+pub(crate) async fn commit_write(
+    state: &ApiState,
+    operation: Operation,
+    checks: Vec<(String, Condition)>,
+    method: &str,
+    route: &str,
+) -> Result<CondOutcome, Response> {
+    if let Some(msg) = oversized(state, &operation) {
+        return Err(too_large(state, method, route, msg));
+    }
     let cluster = state.cluster.read().await;
-    let peers = cluster.peer_addresses.clone();
-    let total_nodes = peers.len() + 1;
-    let write_quorum = (total_nodes / 2) + 1;
+    let node_id = cluster.node_id;
     let leader_id = cluster.leader_id;
+    let peers = cluster.peer_addresses.clone();
     drop(cluster);
+    let key = operation.key().unwrap_or("batch").to_string();
 
-    if node_id == leader_id {
-        if let Err(()) = quorum_write(log_entry.clone(), peers, write_quorum).await {
-            state.metrics.errors.with_label_values(&["quorum_write"]).inc();
-            return (axum::http::StatusCode::INTERNAL_SERVER_ERROR).into_response();
+    let outcome = if checks.is_empty() && !state.store.keeps_siblings() {
+        let entry = LogEntry { ts: state.clock.tick_send(), node_id, operation };
+        state.wal_writer
+            .submit(vec![entry.clone()])
+            .await
+            .map(|mut replaced| CondOutcome::Applied { entry, replaced: replaced.pop().flatten().map(Box::new) })
+    } else if node_id != leader_id {
+        state.metrics.requests.with_label_values(&[method, route, "421"]).inc();
+        let what = if checks.is_empty() { "writes" } else { "conditional writes" };
+        let msg = format!("{} must be sent to the leader (node {})", what, leader_id);
+        return Err((StatusCode::MISDIRECTED_REQUEST, msg).into_response());
+    } else {
+        let entry = LogEntry { ts: 0, node_id, operation };
+        state.wal_writer.submit_if(entry, checks).await
+    };
+
+    let (entry, replaced) = match outcome {
+        Ok(CondOutcome::Applied { entry, replaced }) => (entry, replaced),
+        Ok(failed) => return Ok(failed),
+        Err(e) => {
+            eprintln!("{} {} failed: {}", method, key, e);
+            state.metrics.errors.with_label_values(&["wal"]).inc();
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
         }
-        state.rep_tx.send(log_entry).await;
+    };
+
+    if node_id == leader_id {
+        replicate_write(state, &entry, peers).await?;
     }
 
-    state.metrics.requests.with_label_values(&["PUT", "/key/:key", "200"]).inc();
-    state.metrics.kv_ops.with_label_values(&["put"]).inc();
+    Ok(CondOutcome::Applied { entry, replaced })
+}
 
-    (axum::http::StatusCode::OK).into_response()
+// on the leader, waits for a quorum of peers to take a committed entry, then queues it for
+// batch replication to all of them
+pub(crate) async fn replicate_write(state: &ApiState, entry: &LogEntry, peers: Vec<String>) -> Result<(), Response> {
+    let total_nodes = peers.len() + 1;
+    let write_quorum = (total_nodes / 2) + 1;
+    if let Err(()) = quorum_write(entry.clone(), peers, write_quorum).await {
+        state.metrics.errors.with_label_values(&["quorum_write"]).inc();
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+    let _ = state.rep_tx.send(entry.clone()).await;
+    Ok(())
+}
+
+// 412 carrying the current version of the key, if it has a live one
+fn precondition_failed(state: &ApiState, method: &str, route: &str, conflicts: Vec<(String, Option<Value>)>) -> Response {
+    state.metrics.errors.with_label_values(&["precondition_failed"]).inc();
+    state.metrics.requests.with_label_values(&[method, route, "412"]).inc();
+    let mut resp = StatusCode::PRECONDITION_FAILED.into_response();
+    let current = conflicts.into_iter().next().and_then(|(_, v)| v);
+    if let Some(v) = current.filter(|v| v.is_live(now_ms())) {
+        resp.headers_mut().insert(header::ETAG, etag(v.ts, v.node_id).parse().unwrap());
+    }
+    resp
+}
+
+// JSON form of a value: UTF-8 text goes in `data`, anything else base64-encoded in `data_b64`
+#[derive(Serialize, Deserialize, Default)]
+pub struct DataView {
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_b64: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl DataView {
+    pub fn new(data: Option<Vec<u8>>, content_type: Option<String>) -> Self {
+        match data.map(String::from_utf8) {
+            None => DataView { content_type, ..Default::default() },
+            Some(Ok(text)) => DataView { data: Some(text), content_type, ..Default::default() },
+            Some(Err(e)) => DataView { data_b64: Some(STANDARD.encode(e.into_bytes())), content_type, ..Default::default() },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GetResp {
-    data: Option<String>,
+    #[serde(flatten)]
+    data: DataView,
     ts: u64,
     node_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>, // unix ms of ts, with the hybrid clock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    siblings: Vec<GetResp>, // other live concurrent values, with --conflicts siblings
 }
 
-async fn get_key(
-    State(state): State<ApiState>,
-    Path(key): Path<String>,
-) -> Response {
-    let store = state.store;
-    let local_value = match store.get(&key).await {
-        Some(val) => val,
-        None => {
+impl GetResp {
+    fn new(v: Value, clock: &LamportClock) -> Self {
+        GetResp {
+            siblings: v.siblings.into_iter().map(|s| GetResp::new(s, clock)).collect(),
+            data: DataView::new(v.data, v.content_type),
+            ts: v.ts,
+            node_id: v.node_id,
+            written_at: clock.wall_ms(v.ts),
+            expires_at: v.expires_at,
+        }
+    }
+}
+
+// The current value of the key as served by this node: a follower answers from its own store,
+// the leader does a quorum read. Missing and expired keys come back as tombstones.
+async fn read_key(state: &ApiState, key: &str) -> Result<Value, Response> {
+    let local_value = match state.store.get(key) {
+        // an expired value reads as a tombstone of the same version until it is reaped
+        Ok(Some(val)) if val.is_expired(now_ms()) => Value::tombstone(val.ts, val.node_id),
+        Ok(Some(val)) => val,
+        Ok(None) => {
             let ts = 0;
             let node_id = state.cluster.read().await.node_id;
-            Value { data: None, ts, node_id }
+            Value::tombstone(ts, node_id)
+        }
+        Err(e) => {
+            eprintln!("GET {} failed: {}", key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return Err((StatusCode::INTERNAL_SERVER_ERROR).into_response());
         }
     };
 
@@ -109,83 +430,473 @@ async fn get_key(
     let node_id = cluster.node_id;
     drop(cluster);
 
-    if node_id != leader_id {
-        let body = GetResp { data: local_value.data, ts: local_value.ts, node_id: local_value.node_id };
-        return (axum::http::StatusCode::OK, Json(body)).into_response();
+    // with siblings the leader has applied every acknowledged write itself, and a recovered
+    // node answers with the state it was rolled back to
+    if node_id != leader_id || state.store.keeps_siblings() || state.read_only_at.is_some() {
+        return Ok(local_value);
     }
 
-    let result = quorum_read(key.clone(), local_value.clone(), peers, read_quorum).await;
-    match result {
+    match quorum_read(key.to_string(), local_value, peers, read_quorum).await {
         Ok(fresh) => {
-            let status = if fresh.data.is_some() {
-                axum::http::StatusCode::OK
-            } else {
-                axum::http::StatusCode::NOT_FOUND
-            };
             state.metrics.kv_ops.with_label_values(&["get"]).inc();
-            state.metrics.requests.with_label_values(&["GET", "/key/:key", "200"]).inc();
-            let body = GetResp { data: fresh.data, ts: fresh.ts, node_id: fresh.node_id };
-            (status, Json(body)).into_response()
-        },
+            Ok(fresh)
+        }
         Err(()) => {
             state.metrics.errors.with_label_values(&["quorum_read"]).inc();
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR).into_response()
+            Err((StatusCode::INTERNAL_SERVER_ERROR).into_response())
         }
     }
 }
 
-async fn delete_key(
+#[derive(Deserialize)]
+pub struct ReadQuery {
+    as_of: Option<u64>, // Lamport timestamp to read the key at
+}
+
+async fn get_key(
     State(state): State<ApiState>,
     Path(key): Path<String>,
+    Query(q): Query<ReadQuery>,
 ) -> Response {
-    let ts = state.clock.tick_send();
-    let node_id = state.cluster.read().await.node_id;
-    let log_entry = LogEntry {
-        ts: ts,
-        node_id: node_id,
-        operation: Operation::Delete { key: key.clone() },
+    if let Some(ts) = q.as_of {
+        if state.store.keeps_siblings() {
+            return as_of_with_siblings(&state, "/key/:key");
+        }
+        return get_key_as_of(&state, &key, ts);
+    }
+    if state.store.keeps_siblings() {
+        return get_key_siblings(&state, &key);
+    }
+    let value = match read_key(&state, &key).await {
+        Ok(value) => value,
+        Err(resp) => return resp,
     };
 
-    {
-        let mut wal = state.wal.lock().await;
+    // followers always answer 200 so the leader's quorum read can use tombstones too
+    let is_leader = {
+        let c = state.cluster.read().await;
+        c.node_id == c.leader_id
+    };
+    state.metrics.requests.with_label_values(&["GET", "/key/:key", "200"]).inc();
+    if !is_leader {
+        return (StatusCode::OK, Json(GetResp::new(value, &state.clock))).into_response();
+    }
+    if value.data.is_none() {
+        return (StatusCode::NOT_FOUND, Json(GetResp::new(value, &state.clock))).into_response();
+    }
+    let tag = etag(value.ts, value.node_id);
+    (StatusCode::OK, [(header::ETAG, tag)], Json(GetResp::new(value, &state.clock))).into_response()
+}
+
+// The record's live siblings, newest first, and the context a write replacing all of them
+// must carry
+fn live_siblings(record: Option<Value>) -> (Vec<Value>, VersionVector) {
+    let context = record.as_ref().map(Value::causal_context).unwrap_or_default();
+    let now = now_ms();
+    let live = record
+        .map(Value::into_siblings)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|v| v.is_live(now))
+        .collect();
+    (live, context)
+}
 
-        // tests chaos injection
-        wal.append(&log_entry).unwrap();
-        if state.chaos_before_sync_ms > 0 {
-            tokio::time::sleep(std::time::Duration::from_millis(state.chaos_before_sync_ms)).await;
+// With --conflicts siblings: the newest live value with the others in `siblings`, plus the
+// X-Context to send back with the write that resolves them. The leader has every write, so
+// there is no quorum read.
+fn get_key_siblings(state: &ApiState, key: &str) -> Response {
+    let (mut live, context) = match state.store.get(key) {
+        Ok(record) => live_siblings(record),
+        Err(e) => {
+            eprintln!("GET {} failed: {}", key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
         }
-        wal.sync().unwrap();
+    };
+    state.metrics.kv_ops.with_label_values(&["get"]).inc();
+    let context = (HeaderName::from_static(CONTEXT_HEADER), format_context(&context));
+    if live.is_empty() {
+        state.metrics.requests.with_label_values(&["GET", "/key/:key", "404"]).inc();
+        return (StatusCode::NOT_FOUND, [context]).into_response();
+    }
+    if live.len() > 1 {
+        state.metrics.kv_ops.with_label_values(&["siblings"]).inc();
+    }
+    let mut head = live.remove(0);
+    head.siblings = live;
+    state.metrics.requests.with_label_values(&["GET", "/key/:key", "200"]).inc();
+    let tag = (header::ETAG, etag(head.ts, head.node_id));
+    (StatusCode::OK, [tag, context], Json(GetResp::new(head, &state.clock))).into_response()
+}
+
+// history keeps one version per key, so it can't say which siblings a key had back then
+fn as_of_with_siblings(state: &ApiState, route: &str) -> Response {
+    state.metrics.requests.with_label_values(&["GET", route, "400"]).inc();
+    (StatusCode::BAD_REQUEST, "as_of reads need --conflicts lww").into_response()
+}
+
+// Reads the version that was latest at Lamport time `ts` from this node's store, live if it
+// was then (see ReadAt). 410 means the key's versions from back then are no longer retained.
+fn get_key_as_of(state: &ApiState, key: &str, ts: u64) -> Response {
+    let value = match state.store.get_as_of(key, ts) {
+        Ok(AsOf::Found(value)) => value,
+        Ok(AsOf::Missing) => Value::tombstone(0, 0),
+        Ok(AsOf::Truncated) => {
+            state.metrics.requests.with_label_values(&["GET", "/key/:key", "410"]).inc();
+            return (StatusCode::GONE, "version no longer retained").into_response();
+        }
+        Err(e) => {
+            eprintln!("GET {} as of {} failed: {}", key, ts, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+        }
+    };
+
+    state.metrics.kv_ops.with_label_values(&["get"]).inc();
+    let at = ReadAt { ts, wall_ms: state.clock.wall_ms(ts) };
+    if !at.sees_live(&value) {
+        state.metrics.requests.with_label_values(&["GET", "/key/:key", "404"]).inc();
+        return StatusCode::NOT_FOUND.into_response();
     }
+    state.metrics.requests.with_label_values(&["GET", "/key/:key", "200"]).inc();
+    let tag = etag(value.ts, value.node_id);
+    (StatusCode::OK, [(header::ETAG, tag)], Json(GetResp::new(value, &state.clock))).into_response()
+}
 
-    let store = state.store;
-    let result = store.delete(&key, ts, node_id).await;
-    let (status, resp) = if result.is_some() {
+#[derive(Serialize)]
+pub struct VersionView {
+    #[serde(flatten)]
+    data: DataView,
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+    deleted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResp {
+    key: String,
+    versions: Vec<VersionView>, // newest first
+}
+
+// every version of the key this node retains, tombstones included
+async fn key_history(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> Response {
+    let versions = match state.store.history(&key) {
+        Ok(versions) => versions,
+        Err(e) => {
+            eprintln!("History of {} failed: {}", key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+        }
+    };
+    if versions.is_empty() {
+        state.metrics.requests.with_label_values(&["GET", "/key/:key/history", "404"]).inc();
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let versions = versions
+        .into_iter()
+        .map(|v| VersionView {
+            deleted: v.data.is_none(),
+            data: DataView::new(v.data, v.content_type),
+            ts: v.ts,
+            node_id: v.node_id,
+            written_at: state.clock.wall_ms(v.ts),
+            expires_at: v.expires_at,
+        })
+        .collect();
+    state.metrics.kv_ops.with_label_values(&["history"]).inc();
+    state.metrics.requests.with_label_values(&["GET", "/key/:key/history", "200"]).inc();
+    (StatusCode::OK, Json(HistoryResp { key, versions })).into_response()
+}
+
+// Returns the stored bytes unchanged, with the Content-Type they were written with. Siblings
+// can't be told apart in a raw body, so a key that has them answers 300 and is read as JSON.
+async fn get_raw(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> Response {
+    let value = if state.store.keeps_siblings() {
+        let (mut live, context) = match state.store.get(&key) {
+            Ok(record) => live_siblings(record),
+            Err(e) => {
+                eprintln!("GET {} failed: {}", key, e);
+                state.metrics.errors.with_label_values(&["storage"]).inc();
+                return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+            }
+        };
+        if live.len() > 1 {
+            state.metrics.requests.with_label_values(&["GET", "/raw/:key", "300"]).inc();
+            let context = (HeaderName::from_static(CONTEXT_HEADER), format_context(&context));
+            return (StatusCode::MULTIPLE_CHOICES, [context], "key has siblings; read it from /key/:key").into_response();
+        }
+        live.pop().unwrap_or_else(|| Value::tombstone(0, 0))
+    } else {
+        match read_key(&state, &key).await {
+            Ok(value) => value,
+            Err(resp) => return resp,
+        }
+    };
+    let Some(data) = value.data else {
+        state.metrics.requests.with_label_values(&["GET", "/raw/:key", "404"]).inc();
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    state.metrics.requests.with_label_values(&["GET", "/raw/:key", "200"]).inc();
+    let content_type = value.content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, content_type), (header::ETAG, etag(value.ts, value.node_id))],
+        data,
+    ).into_response()
+}
+
+const LIST_DEFAULT_LIMIT: usize = 100;
+const LIST_MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    prefix: String,
+    start: Option<String>, // inclusive
+    end: Option<String>,   // exclusive
+    limit: Option<usize>,
+    cursor: Option<String>, // next_cursor of the previous page
+    as_of: Option<u64>,     // list every key as of this Lamport timestamp
+}
+
+#[derive(Serialize)]
+pub struct ListItem {
+    key: String,
+    #[serde(flatten)]
+    data: DataView,
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ListResp {
+    items: Vec<ListItem>,
+    next_cursor: Option<String>,
+}
+
+// lists live keys in order from this node's store; pass next_cursor back as `cursor` for
+// the following page
+async fn list_keys(
+    State(state): State<ApiState>,
+    Query(q): Query<ListQuery>,
+) -> Response {
+    if q.as_of.is_some() && state.store.keeps_siblings() {
+        return as_of_with_siblings(&state, "/keys");
+    }
+    let limit = q.limit.unwrap_or(LIST_DEFAULT_LIMIT).clamp(1, LIST_MAX_LIMIT);
+    let as_of = q.as_of.map(|ts| ReadAt { ts, wall_ms: state.clock.wall_ms(ts) });
+    let page = match state.store.scan_live(
+        &q.prefix,
+        q.start.as_deref(),
+        q.end.as_deref(),
+        q.cursor.as_deref(),
+        limit,
+        as_of,
+    ) {
+        Ok(page) => page,
+        Err(e) => {
+            eprintln!("Listing keys failed: {}", e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+        }
+    };
+
+    let items = page.items
+        .into_iter()
+        .map(|(key, v)| ListItem {
+            key,
+            written_at: state.clock.wall_ms(v.ts),
+            data: DataView::new(v.data, v.content_type),
+            ts: v.ts,
+            node_id: v.node_id,
+        })
+        .collect();
+
+    state.metrics.kv_ops.with_label_values(&["list"]).inc();
+    state.metrics.requests.with_label_values(&["GET", "/keys", "200"]).inc();
+    (StatusCode::OK, Json(ListResp { items, next_cursor: page.next })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct IndexQuery {
+    value: String,
+    limit: Option<usize>,
+    cursor: Option<String>, // next_cursor of the previous page
+}
+
+// keys whose indexed field holds `value`, with their values, in key order from this node's
+// store; 404 if no index has that name
+async fn index_query(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Query(q): Query<IndexQuery>,
+) -> Response {
+    let limit = q.limit.unwrap_or(LIST_DEFAULT_LIMIT).clamp(1, LIST_MAX_LIMIT);
+    let page = match state.store.index_lookup(&name, &q.value, q.cursor.as_deref(), limit) {
+        Ok(Some(page)) => page,
+        Ok(None) => {
+            state.metrics.requests.with_label_values(&["GET", "/index/:name", "404"]).inc();
+            return (StatusCode::NOT_FOUND, format!("no index named {}", name)).into_response();
+        }
+        Err(e) => {
+            eprintln!("Index {} lookup failed: {}", name, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+        }
+    };
+
+    let items = page.items
+        .into_iter()
+        .map(|(key, v)| ListItem {
+            key,
+            written_at: state.clock.wall_ms(v.ts),
+            data: DataView::new(v.data, v.content_type),
+            ts: v.ts,
+            node_id: v.node_id,
+        })
+        .collect();
+
+    state.metrics.kv_ops.with_label_values(&["index_lookup"]).inc();
+    state.metrics.requests.with_label_values(&["GET", "/index/:name", "200"]).inc();
+    (StatusCode::OK, Json(ListResp { items, next_cursor: page.next })).into_response()
+}
+
+async fn delete_key(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    matched: MatchedPath,
+    headers: HeaderMap,
+) -> Response {
+    // also serves DELETE /raw/:key
+    let route = matched.as_str();
+    let Some(conditions) = conditions_from(&headers) else {
+        return (StatusCode::BAD_REQUEST, "malformed If-Match/If-None-Match").into_response();
+    };
+    let Some(context) = context_from(&state, &headers) else {
+        return (StatusCode::BAD_REQUEST, "malformed X-Context").into_response();
+    };
+    if let Some(resp) = refuse_crdt(&state, &key, "DELETE", route) {
+        return resp;
+    }
+    let operation = Operation::Delete { key: key.clone(), context };
+
+    let checks = conditions.into_iter().map(|c| (key.clone(), c)).collect();
+    let replaced = match commit_write(&state, operation, checks, "DELETE", route).await {
+        Ok(CondOutcome::Applied { replaced, .. }) => replaced,
+        Ok(CondOutcome::Failed { conflicts }) => return precondition_failed(&state, "DELETE", route, conflicts),
+        Err(resp) => return resp,
+    };
+
+    // deleting a tombstone or an expired value is a miss
+    let status = if replaced.is_some_and(|v| v.is_live(now_ms())) {
         state.metrics.kv_ops.with_label_values(&["delete"]).inc();
-        (axum::http::StatusCode::OK, axum::http::StatusCode::OK.into_response())
+        StatusCode::OK
     } else {
         state.metrics.errors.with_label_values(&["not_found"]).inc();
-        (axum::http::StatusCode::NOT_FOUND, axum::http::StatusCode::NOT_FOUND.into_response())
+        StatusCode::NOT_FOUND
     };
 
-    let cluster = state.cluster.read().await;
-    let peers = cluster.peer_addresses.clone();
-    let total_nodes = peers.len() + 1;
-    let write_quorum = (total_nodes / 2) + 1;
-    let leader_id = cluster.leader_id;
-    drop(cluster);
+    let status_label = if status == StatusCode::OK { "200" } else { "404" };
+    state.metrics.requests.with_label_values(&["DELETE", route, status_label]).inc();
 
-    if node_id == leader_id {
-        if let Err(()) = quorum_write(log_entry.clone(), peers, write_quorum).await {
-            state.metrics.errors.with_label_values(&["quorum_write"]).inc();
-            return (axum::http::StatusCode::INTERNAL_SERVER_ERROR).into_response();
+    status.into_response()
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOp {
+    Put {
+        key: String,
+        value: String,
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+        #[serde(default)]
+        context: Option<String>, // X-Context of the key, with --conflicts siblings
+    },
+    Delete {
+        key: String,
+        #[serde(default)]
+        context: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct BatchBody {
+    pub ops: Vec<BatchOp>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResp {
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+}
+
+// applies all ops under one timestamp, or none of them
+async fn batch(
+    State(state): State<ApiState>,
+    Json(body): Json<BatchBody>,
+) -> Response {
+    if body.ops.is_empty() {
+        return (StatusCode::BAD_REQUEST, "empty batch").into_response();
+    }
+
+    let now = now_ms();
+    let mut keys = HashSet::new();
+    let mut ops = Vec::with_capacity(body.ops.len());
+    for op in body.ops {
+        let (BatchOp::Put { key, context, .. } | BatchOp::Delete { key, context }) = &op;
+        // ops share one version, so two on the same key would tie
+        if !keys.insert(key.clone()) {
+            return (StatusCode::BAD_REQUEST, format!("key {} appears twice in the batch", key)).into_response();
         }
-        state.rep_tx.send(log_entry).await;
+        if let Some(resp) = refuse_crdt(&state, key, "POST", "/batch") {
+            return resp;
+        }
+        let context = match context.as_deref().map(parse_context) {
+            _ if !state.store.keeps_siblings() => None,
+            Some(None) => return (StatusCode::BAD_REQUEST, format!("malformed context for key {}", key)).into_response(),
+            Some(parsed) => parsed,
+            None => Some(VersionVector::new()),
+        };
+        ops.push(match op {
+            BatchOp::Put { key, value, ttl_ms, .. } => Operation::Put {
+                key,
+                value: value.into_bytes(),
+                content_type: None,
+                expires_at: ttl_ms.map(|ttl| now + ttl),
+                context,
+            },
+            BatchOp::Delete { key, .. } => Operation::Delete { key, context },
+        });
     }
 
-    let status_label = if status == axum::http::StatusCode::OK { "200" } else { "404" };
-    state.metrics.requests.with_label_values(&["DELETE", "/key/:key", status_label]).inc();
+    // unconditional, so it can't conflict
+    let entry = match commit_write(&state, Operation::Batch { ops }, Vec::new(), "POST", "/batch").await {
+        Ok(CondOutcome::Applied { entry, .. }) => entry,
+        Ok(CondOutcome::Failed { .. }) => return StatusCode::CONFLICT.into_response(),
+        Err(resp) => return resp,
+    };
 
-    resp
+    state.metrics.kv_ops.with_label_values(&["batch"]).inc();
+    state.metrics.requests.with_label_values(&["POST", "/batch", "200"]).inc();
+    (StatusCode::OK, Json(BatchResp { ts: entry.ts, node_id: entry.node_id, written_at: state.clock.wall_ms(entry.ts) })).into_response()
 }
 
 async fn replicate(
@@ -193,65 +904,129 @@ async fn replicate(
     Json(body): Json<ReplicateBody>, 
 ) -> Response {
 
-    // only add fresh entries to WAL and store
+    // only add entries the store would keep to WAL and store; a batch is kept if any of its ops is
     let mut to_apply = Vec::with_capacity(body.entries.len());
     for entry in &body.entries {
-        match &entry.operation {
-            Operation::Put { key, value } => {
-                let cur = state.store.get(key).await;
-                let incoming = Value { data: None, ts: entry.ts, node_id: entry.node_id };
-                if incoming.is_newer_than(cur.as_ref()) {
-                    to_apply.push(entry.clone());
-                }
+        let ops = match &entry.operation {
+            Operation::Batch { ops } => ops.iter().collect(),
+            // purges only match the exact tombstone
+            Operation::Purge { .. } => {
+                to_apply.push(entry.clone());
+                continue;
             }
-            Operation::Delete { key } => {
-                let cur = state.store.get(key).await;
-                let incoming = Value { data: None, ts: entry.ts, node_id: entry.node_id };
-                if incoming.is_newer_than(cur.as_ref()) {
-                    to_apply.push(entry.clone());
+            op => vec![op],
+        };
+        let mut stale = true;
+        for op in ops {
+            match state.store.is_stale(op, entry.ts, entry.node_id) {
+                Ok(true) => {}
+                Ok(false) => {
+                    stale = false;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Replicate read of {} failed: {}", op.key().unwrap_or("batch"), e);
+                    state.metrics.errors.with_label_values(&["storage"]).inc();
+                    return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
                 }
             }
         }
+        if !stale {
+            to_apply.push(entry.clone());
+        }
     }
 
     if to_apply.is_empty() {
         state.metrics.requests.with_label_values(&["POST", "/replicate", "200"]).inc();
-        return (axum::http::StatusCode::OK).into_response();
+        return (StatusCode::OK).into_response();
     }
 
-    {
-        let mut wal = state.wal.lock().await;
-        for entry in &to_apply {
-            wal.append(&entry).unwrap();
-        }
-        wal.sync().unwrap();
+    if let Err(e) = state.wal_writer.submit(to_apply.clone()).await {
+        eprintln!("Replicated write failed: {}", e);
+        state.metrics.errors.with_label_values(&["wal"]).inc();
+        return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
     }
 
-    for entry in to_apply {
+    for entry in &to_apply {
         state.clock.tick_recv(entry.ts);
         match entry.operation {
-            Operation::Put { key, value } => {
-                state.store.put(key, value, entry.ts, entry.node_id).await;
-                state.metrics.kv_ops.with_label_values(&["put"]).inc();
+            Operation::Put { .. } => state.metrics.kv_ops.with_label_values(&["put"]).inc(),
+            Operation::Delete { .. } => state.metrics.kv_ops.with_label_values(&["delete"]).inc(),
+            Operation::Counter { .. } => state.metrics.kv_ops.with_label_values(&["incr"]).inc(),
+            Operation::Set { .. } => state.metrics.kv_ops.with_label_values(&["set_update"]).inc(),
+            Operation::Purge { .. } => state.metrics.kv_ops.with_label_values(&["purge"]).inc(),
+            Operation::Batch { .. } => state.metrics.kv_ops.with_label_values(&["batch"]).inc(),
+        }
+    }
+
+    state.metrics.requests.with_label_values(&["POST", "/replicate", "200"]).inc();
+    StatusCode::OK.into_response()
+}
+
+// tells the leader's tombstone GC which of its tombstones this node already holds
+async fn gc_confirm(
+    State(state): State<ApiState>,
+    Json(body): Json<ConfirmBody>,
+) -> Response {
+    let mut confirmed = Vec::new();
+    for t in body.tombstones {
+        match state.store.get(&t.key) {
+            Ok(cur) => {
+                if cur.is_some() && !Value::tombstone(t.ts, t.node_id).is_newer_than(cur.as_ref()) {
+                    confirmed.push(t.key);
+                }
             }
-            Operation::Delete { key } => {
-                state.store.delete(&key, entry.ts, entry.node_id).await;
-                state.metrics.kv_ops.with_label_values(&["delete"]).inc();
+            Err(e) => {
+                eprintln!("GC confirm read of {} failed: {}", t.key, e);
+                state.metrics.errors.with_label_values(&["storage"]).inc();
+                return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
             }
         }
     }
 
-    state.metrics.requests.with_label_values(&["POST", "/replicate", "200"]).inc();
-    axum::http::StatusCode::OK.into_response()
+    state.metrics.requests.with_label_values(&["POST", "/gc/confirm", "200"]).inc();
+    (StatusCode::OK, Json(ConfirmResp { confirmed })).into_response()
+}
+
+async fn admin_compact(State(state): State<ApiState>) -> Response {
+    match checkpoint(&state.store, &state.clock, &state.wal, &state.snapshot_cfg, true).await {
+        Ok(stats) => {
+            state.metrics.requests.with_label_values(&["POST", "/admin/compact", "200"]).inc();
+            (StatusCode::OK, Json(stats)).into_response()
+        }
+        Err(e) => {
+            eprintln!("Compaction failed: {}", e);
+            state.metrics.errors.with_label_values(&["compaction"]).inc();
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn admin_wal_segments(State(state): State<ApiState>) -> Response {
+    let (dir, keys) = {
+        let wal = state.wal.lock().await;
+        (wal.dir().to_path_buf(), wal.keys().cloned())
+    };
+    match tokio::task::spawn_blocking(move || list_segments(dir, keys.as_ref())).await {
+        Ok(Ok(segments)) => {
+            state.metrics.requests.with_label_values(&["GET", "/admin/wal/segments", "200"]).inc();
+            (StatusCode::OK, Json(segments)).into_response()
+        }
+        Ok(Err(e)) => {
+            state.metrics.errors.with_label_values(&["wal_segments"]).inc();
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
 }
 
 async fn ping() -> Response {
-    (axum::http::StatusCode::OK, "pong").into_response()
+    (StatusCode::OK, "pong").into_response()
 }
 
 async fn health(State(state): State<ApiState>) -> Response {
     let is_alive = state.cluster.read().await.is_alive.clone();
-    (axum::http::StatusCode::OK, axum::Json(is_alive)).into_response()
+    (StatusCode::OK, axum::Json(is_alive)).into_response()
 }
 
 async fn metrics(State(state): State<ApiState>) -> Response {
@@ -259,7 +1034,7 @@ async fn metrics(State(state): State<ApiState>) -> Response {
     let enc = TextEncoder::new();
     enc.encode(&state.metrics.registry.gather(), &mut buffer).unwrap();
     (
-        axum::http::StatusCode::OK,
+        StatusCode::OK,
         [(axum::http::header::CONTENT_TYPE, enc.format_type().to_string())],
         buffer,
     ).into_response()
@@ -0,0 +1,274 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json, response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api::ApiState;
+use crate::api::client::commit_write;
+use crate::store::{CondOutcome, Value};
+use crate::util::{AwSet, Crdt, Operation, now_ms};
+
+// Counters and add-wins sets. Writes are taken by the leader, which logs and replicates them
+// like any other write; replicas join them in whatever order they arrive, so they never
+// conflict. Reads answer from this node.
+
+#[derive(Deserialize)]
+pub struct IncrBody {
+    #[serde(default = "one")]
+    pub by: i64, // negative to decrement
+}
+
+fn one() -> i64 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct MembersBody {
+    members: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CounterResp {
+    value: i64,
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SetResp {
+    members: Vec<String>,
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+}
+
+// The key's counter or set state and the version it was last written at, a deleted one's
+// included. None if the key is missing or a deleted plain value; 409 if it holds something
+// other than a `kind`.
+async fn read_crdt(state: &ApiState, key: &str, kind: &str, method: &str, route: &str) -> Result<Option<(Crdt, Value)>, Response> {
+    let record = match state.store.get(key) {
+        Ok(record) => record,
+        Err(e) => {
+            eprintln!("{} {} failed: {}", method, key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    match record {
+        Some(mut v) => match v.crdt.take() {
+            Some(crdt) if crdt.kind() == kind => Ok(Some((*crdt, v))),
+            Some(crdt) => Err(wrong_type(state, key, crdt.kind(), method, route)),
+            None if v.is_live(now_ms()) => Err(wrong_type(state, key, "plain value", method, route)),
+            None => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+fn wrong_type(state: &ApiState, key: &str, holds: &str, method: &str, route: &str) -> Response {
+    state.metrics.errors.with_label_values(&["wrong_type"]).inc();
+    state.metrics.requests.with_label_values(&[method, route, "409"]).inc();
+    (StatusCode::CONFLICT, format!("key {} holds a {}", key, holds)).into_response()
+}
+
+// 421 unless this node is the leader, the only one that replicates what it logs
+async fn refuse_on_follower(state: &ApiState, method: &str, route: &str) -> Option<Response> {
+    let cluster = state.cluster.read().await;
+    if cluster.node_id == cluster.leader_id {
+        return None;
+    }
+    state.metrics.requests.with_label_values(&[method, route, "421"]).inc();
+    let msg = format!("counter and set writes must be sent to the leader (node {})", cluster.leader_id);
+    Some((StatusCode::MISDIRECTED_REQUEST, msg).into_response())
+}
+
+async fn write_crdt(state: &ApiState, operation: Operation, method: &str, route: &str) -> Result<(), Response> {
+    match commit_write(state, operation, Vec::new(), method, route).await {
+        Ok(CondOutcome::Applied { .. }) => Ok(()),
+        // unconditional, so it can't fail a check
+        Ok(CondOutcome::Failed { .. }) => Err(StatusCode::CONFLICT.into_response()),
+        Err(resp) => Err(resp),
+    }
+}
+
+fn counter_resp(state: &ApiState, current: Option<(Crdt, Value)>) -> Response {
+    let (value, ts, node_id) = match current {
+        Some((Crdt::Counter(counter), v)) => (counter.value(), v.ts, v.node_id),
+        _ => (0, 0, 0),
+    };
+    let written_at = state.clock.wall_ms(ts);
+    (StatusCode::OK, Json(CounterResp { value, ts, node_id, written_at })).into_response()
+}
+
+fn set_resp(state: &ApiState, current: Option<(Crdt, Value)>) -> Response {
+    let (members, ts, node_id) = match current {
+        Some((Crdt::Set(set), v)) => (set.members(), v.ts, v.node_id),
+        _ => (Vec::new(), 0, 0),
+    };
+    let written_at = state.clock.wall_ms(ts);
+    (StatusCode::OK, Json(SetResp { members, ts, node_id, written_at })).into_response()
+}
+
+// adds `by` to the counter, creating it at 0 first; answers with the count this node sees
+pub async fn counter_incr(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(body): Json<IncrBody>,
+) -> Response {
+    const ROUTE: &str = "/counter/:key/incr";
+    if let Some(resp) = refuse_on_follower(&state, "POST", ROUTE).await {
+        return resp;
+    }
+    if let Err(resp) = read_crdt(&state, &key, "counter", "POST", ROUTE).await {
+        return resp;
+    }
+    let node_id = state.cluster.read().await.node_id;
+    let delta = match state.store.counter_delta(&key, node_id, body.by) {
+        Ok(delta) => delta,
+        Err(e) => {
+            eprintln!("POST {} failed: {}", key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if let Err(resp) = write_crdt(&state, Operation::Counter { key: key.clone(), delta }, "POST", ROUTE).await {
+        return resp;
+    }
+
+    let current = match read_crdt(&state, &key, "counter", "POST", ROUTE).await {
+        Ok(current) => current,
+        Err(resp) => return resp,
+    };
+    state.metrics.kv_ops.with_label_values(&["incr"]).inc();
+    state.metrics.requests.with_label_values(&["POST", ROUTE, "200"]).inc();
+    counter_resp(&state, current)
+}
+
+// a counter nobody has incremented reads as 0
+pub async fn counter_get(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> Response {
+    let current = match read_crdt(&state, &key, "counter", "GET", "/counter/:key").await {
+        Ok(current) => current,
+        Err(resp) => return resp,
+    };
+    state.metrics.kv_ops.with_label_values(&["get"]).inc();
+    state.metrics.requests.with_label_values(&["GET", "/counter/:key", "200"]).inc();
+    counter_resp(&state, current)
+}
+
+pub async fn set_add(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(body): Json<MembersBody>,
+) -> Response {
+    update_set(&state, key, body.members, true, "/set/:key/add").await
+}
+
+// Removes the members' adds this node has seen. An add made concurrently elsewhere wins
+// and keeps its member in the set.
+pub async fn set_remove(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(body): Json<MembersBody>,
+) -> Response {
+    update_set(&state, key, body.members, false, "/set/:key/remove").await
+}
+
+async fn update_set(state: &ApiState, key: String, members: Vec<String>, add: bool, route: &str) -> Response {
+    if let Some(resp) = refuse_on_follower(state, "POST", route).await {
+        return resp;
+    }
+    let current = match read_crdt(state, &key, "set", "POST", route).await {
+        Ok(current) => current,
+        Err(resp) => return resp,
+    };
+    let delta = if add {
+        // a fresh tick, so every add gets its own dot
+        let node_id = state.cluster.read().await.node_id;
+        AwSet::adding(&members, node_id, state.clock.tick_send())
+    } else {
+        match &current {
+            Some((Crdt::Set(set), _)) => set.removing(&members),
+            _ => AwSet::default(),
+        }
+    };
+
+    let current = if delta.is_empty() {
+        // nothing to remove
+        current
+    } else {
+        if let Err(resp) = write_crdt(state, Operation::Set { key: key.clone(), delta }, "POST", route).await {
+            return resp;
+        }
+        match read_crdt(state, &key, "set", "POST", route).await {
+            Ok(current) => current,
+            Err(resp) => return resp,
+        }
+    };
+    let op = if add { "set_add" } else { "set_remove" };
+    state.metrics.kv_ops.with_label_values(&[op]).inc();
+    state.metrics.requests.with_label_values(&["POST", route, "200"]).inc();
+    set_resp(state, current)
+}
+
+pub async fn set_get(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> Response {
+    let current = match read_crdt(&state, &key, "set", "GET", "/set/:key").await {
+        Ok(current) => current,
+        Err(resp) => return resp,
+    };
+    state.metrics.kv_ops.with_label_values(&["get"]).inc();
+    state.metrics.requests.with_label_values(&["GET", "/set/:key", "200"]).inc();
+    set_resp(&state, current)
+}
+
+pub async fn counter_delete(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> Response {
+    delete_crdt(&state, key, "counter", "/counter/:key").await
+}
+
+pub async fn set_delete(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> Response {
+    delete_crdt(&state, key, "set", "/set/:key").await
+}
+
+// Deletes the counter or set as this node sees it. Increments and adds the delete didn't see,
+// made concurrently elsewhere, survive it and bring the key back with just those. Once GC has
+// collected the tombstone the key takes any kind of write again.
+async fn delete_crdt(state: &ApiState, key: String, kind: &str, route: &str) -> Response {
+    if let Some(resp) = refuse_on_follower(state, "DELETE", route).await {
+        return resp;
+    }
+    let crdt = match read_crdt(state, &key, kind, "DELETE", route).await {
+        Ok(Some((crdt, _))) if !crdt.is_deleted() => crdt,
+        Ok(_) => {
+            state.metrics.errors.with_label_values(&["not_found"]).inc();
+            state.metrics.requests.with_label_values(&["DELETE", route, "404"]).inc();
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(resp) => return resp,
+    };
+    let operation = match crdt.deleting() {
+        Crdt::Counter(delta) => Operation::Counter { key, delta },
+        Crdt::Set(delta) => Operation::Set { key, delta },
+    };
+    if let Err(resp) = write_crdt(state, operation, "DELETE", route).await {
+        return resp;
+    }
+    state.metrics.kv_ops.with_label_values(&["delete"]).inc();
+    state.metrics.requests.with_label_values(&["DELETE", route, "200"]).inc();
+    StatusCode::OK.into_response()
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::api::ApiState;
+use crate::store::{CondOutcome, Condition};
+use crate::util::{LogEntry, Operation, now_ms};
+
+const REAP_EVERY_MS: u64 = 1000;
+
+// Turns expired keys into tombstones. Only the leader writes them; they go through the WAL
+// and out to the followers with regular replication. Every node already hides expired values
+// from reads, so the tombstones don't need a quorum. Each tombstone is a conditional delete,
+// written only if the expired version is still current and stamped with a fresh tick after
+// it, so it orders like any other write; a key written again in the meantime is left alone.
+// Followers only drain their expiry list.
+pub fn spawn_expiry_reaper(state: ApiState) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_millis(REAP_EVERY_MS));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            ticker.tick().await;
+
+            let expired = match state.store.take_expired(now_ms()) {
+                Ok(expired) => expired,
+                Err(e) => {
+                    eprintln!("Expiry scan failed: {}", e);
+                    continue;
+                }
+            };
+            let cluster = state.cluster.read().await;
+            let node_id = cluster.node_id;
+            let is_leader = node_id == cluster.leader_id;
+            drop(cluster);
+            if expired.is_empty() || !is_leader {
+                continue;
+            }
+
+            for (key, value) in expired {
+                let entry = LogEntry {
+                    ts: 0, // stamped by the writer
+                    node_id,
+                    // with siblings the tombstone replaces just the expired one
+                    operation: Operation::Delete {
+                        key: key.clone(),
+                        context: state.store.keeps_siblings().then(|| value.clock()),
+                    },
+                };
+                let check = (key.clone(), Condition::Unchanged(value.ts, value.node_id));
+                match state.wal_writer.submit_if(entry, vec![check]).await {
+                    Ok(CondOutcome::Applied { entry, .. }) => {
+                        state.metrics.kv_ops.with_label_values(&["expire"]).inc();
+                        let _ = state.rep_tx.send(entry).await;
+                    }
+                    // written again, or deleted, since it expired
+                    Ok(CondOutcome::Failed { .. }) => {}
+                    Err(e) => {
+                        eprintln!("Writing an expiry tombstone failed: {}", e);
+                        state.metrics.errors.with_label_values(&["wal"]).inc();
+                        // retry on the next tick
+                        if let Some(at) = value.expires_at {
+                            state.store.track_expiry(&key, at);
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
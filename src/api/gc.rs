@@ -0,0 +1,114 @@
+use std::{collections::HashSet, time::Duration};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::api::ApiState;
+use crate::util::{LogEntry, Operation, now_ms};
+
+const GC_EVERY_SECS: u64 = 10;
+const GC_BATCH: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TombstoneRef {
+    pub key: String,
+    pub ts: u64,
+    pub node_id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmBody {
+    pub tombstones: Vec<TombstoneRef>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmResp {
+    pub confirmed: Vec<String>, // keys holding that tombstone or something newer
+}
+
+// Purges tombstones older than `grace`. Only the leader decides: it asks every peer whether
+// it already holds each tombstone (or a newer write) and purges just the keys all of them
+// confirm, by logging a Purge that is replicated like any other write. A peer that is down or
+// doesn't answer blocks the whole round, so it can't come back with an older write for a key
+// the others have forgotten.
+pub fn spawn_tombstone_gc(state: ApiState, grace: Duration) -> tokio::task::JoinHandle<()> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("reqwest client");
+
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(GC_EVERY_SECS));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            ticker.tick().await;
+
+            let cluster = state.cluster.read().await;
+            let is_leader = cluster.node_id == cluster.leader_id;
+            let peers = cluster.peer_addresses.clone();
+            drop(cluster);
+            if !is_leader {
+                continue;
+            }
+
+            let cutoff = now_ms().saturating_sub(grace.as_millis() as u64);
+            let candidates = match state.store.old_tombstones(cutoff, GC_BATCH) {
+                Ok(c) if !c.is_empty() => c,
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("Tombstone scan failed: {}", e);
+                    continue;
+                }
+            };
+
+            let body = ConfirmBody {
+                tombstones: candidates
+                    .iter()
+                    .map(|(key, v)| TombstoneRef { key: key.clone(), ts: v.ts, node_id: v.node_id })
+                    .collect(),
+            };
+            let mut confirmed: HashSet<String> = candidates.iter().map(|(key, _)| key.clone()).collect();
+            for peer in &peers {
+                match confirm_with(&client, peer, &body).await {
+                    Ok(keys) => {
+                        let keys: HashSet<String> = keys.into_iter().collect();
+                        confirmed.retain(|k| keys.contains(k));
+                    }
+                    Err(e) => {
+                        eprintln!("Tombstone GC skipped, {} did not confirm: {}", peer, e);
+                        confirmed.clear();
+                        break;
+                    }
+                }
+            }
+            if confirmed.is_empty() {
+                continue;
+            }
+
+            let purges: Vec<LogEntry> = candidates
+                .into_iter()
+                .filter(|(key, _)| confirmed.contains(key))
+                .map(|(key, v)| LogEntry { ts: v.ts, node_id: v.node_id, operation: Operation::Purge { key } })
+                .collect();
+
+            if let Err(e) = state.wal_writer.submit(purges.clone()).await {
+                eprintln!("Writing tombstone purges failed: {}", e);
+                state.metrics.errors.with_label_values(&["wal"]).inc();
+                continue;
+            }
+
+            println!("Purged {} tombstones", purges.len());
+            for entry in purges {
+                state.metrics.kv_ops.with_label_values(&["purge"]).inc();
+                let _ = state.rep_tx.send(entry).await;
+            }
+        }
+    })
+}
+
+async fn confirm_with(client: &Client, peer: &str, body: &ConfirmBody) -> anyhow::Result<Vec<String>> {
+    let url = format!("{}/gc/confirm", peer.trim_end_matches('/'));
+    let resp = client.post(&url).json(body).send().await?.error_for_status()?;
+    Ok(resp.json::<ConfirmResp>().await?.confirmed)
+}
@@ -1,4 +1,4 @@
-use prometheus::{Encoder, TextEncoder, Registry, IntCounterVec};
+use prometheus::{Registry, IntCounterVec, IntGaugeVec, Histogram, HistogramOpts};
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
@@ -6,6 +6,14 @@ pub struct Metrics {
     pub kv_ops: IntCounterVec,
     pub requests: IntCounterVec,
     pub errors: IntCounterVec,
+    pub wal_durability: IntGaugeVec,
+    pub wal_fsync_seconds: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
 }
 
 impl Metrics {
@@ -23,11 +31,21 @@ impl Metrics {
             prometheus::Opts::new("errors", "Total API Errors"),
             &["kind"],
         ).unwrap();
+        let wal_durability = IntGaugeVec::new(
+            prometheus::Opts::new("wal_durability_mode", "Active WAL durability policy (1 = active)"),
+            &["mode"],
+        ).unwrap();
+        let wal_fsync_seconds = Histogram::with_opts(
+            HistogramOpts::new("wal_fsync_seconds", "WAL fsync latency")
+                .buckets(prometheus::exponential_buckets(0.0001, 2.0, 16).unwrap()),
+        ).unwrap();
 
         registry.register(Box::new(kv_ops.clone())).unwrap();
         registry.register(Box::new(requests.clone())).unwrap();
         registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(wal_durability.clone())).unwrap();
+        registry.register(Box::new(wal_fsync_seconds.clone())).unwrap();
 
-        Self { registry, kv_ops, requests, errors }
+        Self { registry, kv_ops, requests, errors, wal_durability, wal_fsync_seconds }
     }
 }
\ No newline at end of file
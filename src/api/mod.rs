@@ -1,7 +1,17 @@
+pub mod atomic;
+pub mod backup;
 pub mod client;
+pub mod crdt;
+pub mod expiry;
+pub mod gc;
 pub mod metrics;
 pub mod state;
+pub mod txn;
+pub mod watch;
 
 pub use state::ApiState;
 pub use metrics::Metrics;
-pub use client::RouterBuilder;
\ No newline at end of file
+pub use client::RouterBuilder;
+pub use expiry::spawn_expiry_reaper;
+pub use gc::spawn_tombstone_gc;
+pub use txn::TxnRegistry;
\ No newline at end of file
@@ -1,8 +1,8 @@
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock, mpsc};
-use crate::store::{Store, LamportClock, Wal};
+use crate::store::{Store, LamportClock, Wal, WalWriter, SnapshotCfg};
 use crate::cluster::{ClusterState};
-use crate::api::{Metrics};
+use crate::api::{Metrics, TxnRegistry};
 use crate::util::{LogEntry};
 
 #[derive(Clone, Debug)]
@@ -11,8 +11,11 @@ pub struct ApiState {
     pub clock: Arc<LamportClock>,
     pub cluster: Arc<RwLock<ClusterState>>,
     pub metrics: Metrics,
-    pub wal: Arc<Mutex<Wal>>,
+    pub wal: Arc<Mutex<Wal>>, // appends go through wal_writer; the lock is for checkpoints
+    pub wal_writer: WalWriter,
     pub rep_tx: mpsc::Sender<LogEntry>,
-
-    pub chaos_before_sync_ms: u64, // testing chaos injection
+    pub snapshot_cfg: SnapshotCfg,
+    pub txns: Arc<TxnRegistry>,
+    pub max_value_bytes: usize, // longest value a write may carry or an atomic op produce
+    pub read_only_at: Option<u64>, // serving a point-in-time recovery to this ts: writes are refused
 }
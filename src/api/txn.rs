@@ -0,0 +1,360 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json, response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::api::ApiState;
+use crate::api::client::{DataView, PutBody, commit_write, oversized, refuse_crdt, too_large};
+use crate::store::{Condition, CondOutcome};
+use crate::util::{Operation, VersionVector, MAX_WRITE_BYTES, now_ms};
+
+// transactions untouched for this long are dropped
+const TXN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// beyond this many open transactions, begin is refused until some commit, abort or expire
+const TXN_MAX_OPEN: usize = 10_000;
+// a commit logs every buffered write as one record
+const TXN_MAX_WRITES: usize = 1000;
+
+#[derive(Debug)]
+struct Txn {
+    reads: HashMap<String, Option<(u64, u64)>>, // version first read per key; None = absent
+    writes: BTreeMap<String, Operation>,        // buffered until commit, last write per key wins
+    contexts: HashMap<String, VersionVector>,   // causal context read per key, with siblings
+    touched: Instant,
+}
+
+// open optimistic transactions on the leader
+#[derive(Debug)]
+pub struct TxnRegistry {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Txn>>,
+}
+
+impl Default for TxnRegistry {
+    fn default() -> Self {
+        TxnRegistry::new()
+    }
+}
+
+impl TxnRegistry {
+    pub fn new() -> Self {
+        TxnRegistry {
+            // start from the clock so ids handed out before a restart aren't reused
+            next_id: AtomicU64::new(now_ms()),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // None when TXN_MAX_OPEN transactions are already open
+    fn begin(&self) -> Option<u64> {
+        let mut open = self.open.lock().unwrap();
+        open.retain(|_, txn| txn.touched.elapsed() < TXN_IDLE_TIMEOUT);
+        if open.len() >= TXN_MAX_OPEN {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        open.insert(id, Txn {
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+            contexts: HashMap::new(),
+            touched: Instant::now(),
+        });
+        Some(id)
+    }
+
+    fn with<R>(&self, id: u64, f: impl FnOnce(&mut Txn) -> R) -> Option<R> {
+        let mut open = self.open.lock().unwrap();
+        let txn = open.get_mut(&id).filter(|txn| txn.touched.elapsed() < TXN_IDLE_TIMEOUT)?;
+        txn.touched = Instant::now();
+        Some(f(txn))
+    }
+
+    fn take(&self, id: u64) -> Option<Txn> {
+        self.open.lock().unwrap().remove(&id).filter(|txn| txn.touched.elapsed() < TXN_IDLE_TIMEOUT)
+    }
+}
+
+impl Txn {
+    // buffers `op` as the write to `key`, unless that takes the transaction over a cap
+    fn buffer(&mut self, key: String, op: Operation) -> Result<(), String> {
+        let replaced = self.writes.get(&key).map_or(0, Operation::size);
+        let bytes = self.writes.values().map(Operation::size).sum::<usize>() - replaced + op.size();
+        if !self.writes.contains_key(&key) && self.writes.len() >= TXN_MAX_WRITES {
+            return Err(format!("a transaction holds at most {} writes", TXN_MAX_WRITES));
+        }
+        if bytes > MAX_WRITE_BYTES {
+            return Err(format!("a transaction's writes hold at most {} bytes of keys and values", MAX_WRITE_BYTES));
+        }
+        self.writes.insert(key, op);
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct BeginResp {
+    txn_id: u64,
+}
+
+#[derive(Serialize)]
+pub struct TxnGetResp {
+    #[serde(flatten)]
+    data: DataView,
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CommitResp {
+    ts: Option<u64>, // version of the writes, if there were any
+    node_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ConflictResp {
+    error: String,
+    conflicts: Vec<String>,
+}
+
+fn unknown_txn() -> Response {
+    (StatusCode::NOT_FOUND, "unknown or expired transaction").into_response()
+}
+
+
+
+// transactions are checked against the leader's store, so only the leader opens them
+pub async fn begin_txn(State(state): State<ApiState>) -> Response {
+    let cluster = state.cluster.read().await;
+    let leader_id = cluster.leader_id;
+    let is_leader = cluster.node_id == leader_id;
+    drop(cluster);
+    if !is_leader {
+        state.metrics.requests.with_label_values(&["POST", "/txn", "421"]).inc();
+        let msg = format!("transactions must be started on the leader (node {})", leader_id);
+        return (StatusCode::MISDIRECTED_REQUEST, msg).into_response();
+    }
+
+    let Some(txn_id) = state.txns.begin() else {
+        state.metrics.errors.with_label_values(&["txn_limit"]).inc();
+        state.metrics.requests.with_label_values(&["POST", "/txn", "503"]).inc();
+        let msg = format!("{} transactions are already open; commit or abort some and retry", TXN_MAX_OPEN);
+        return (StatusCode::SERVICE_UNAVAILABLE, msg).into_response();
+    };
+    state.metrics.requests.with_label_values(&["POST", "/txn", "200"]).inc();
+    (StatusCode::OK, Json(BeginResp { txn_id })).into_response()
+}
+
+// reads the transaction's own writes first, otherwise the committed value, whose version is
+// remembered for the check at commit
+pub async fn txn_get(
+    State(state): State<ApiState>,
+    Path((id, key)): Path<(u64, String)>,
+) -> Response {
+    let buffered = match state.txns.with(id, |txn| txn.writes.get(&key).cloned()) {
+        Some(buffered) => buffered,
+        None => return unknown_txn(),
+    };
+    let node_id = state.cluster.read().await.node_id;
+    match buffered {
+        Some(Operation::Put { value, content_type, .. }) => {
+            let data = DataView::new(Some(value), content_type);
+            return (StatusCode::OK, Json(TxnGetResp { data, ts: 0, node_id, written_at: None })).into_response();
+        }
+        Some(_) => return StatusCode::NOT_FOUND.into_response(),
+        None => {}
+    }
+
+    let current = match state.store.get(&key) {
+        Ok(cur) => {
+            if let Some(record) = cur.as_ref().filter(|_| state.store.keeps_siblings()) {
+                let context = record.causal_context();
+                if state.txns.with(id, |txn| { txn.contexts.entry(key.clone()).or_insert(context); }).is_none() {
+                    return unknown_txn();
+                }
+            }
+            cur.filter(|v| v.is_live(now_ms()))
+        }
+        Err(e) => {
+            eprintln!("Transaction read of {} failed: {}", key, e);
+            state.metrics.errors.with_label_values(&["storage"]).inc();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let version = current.as_ref().map(|v| (v.ts, v.node_id));
+    if state.txns.with(id, |txn| { txn.reads.entry(key.clone()).or_insert(version); }).is_none() {
+        return unknown_txn();
+    }
+
+    state.metrics.kv_ops.with_label_values(&["get"]).inc();
+    state.metrics.requests.with_label_values(&["GET", "/txn/:id/key/:key", "200"]).inc();
+    match current {
+        Some(v) => {
+            let data = DataView::new(v.data, v.content_type);
+            (StatusCode::OK, Json(TxnGetResp { data, ts: v.ts, node_id: v.node_id, written_at: state.clock.wall_ms(v.ts) })).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn txn_put(
+    State(state): State<ApiState>,
+    Path((id, key)): Path<(u64, String)>,
+    Json(body): Json<PutBody>,
+) -> Response {
+    if let Some(resp) = refuse_crdt(&state, &key, "PUT", "/txn/:id/key/:key") {
+        return resp;
+    }
+    let op = Operation::Put {
+        key: key.clone(),
+        value: body.value.into_bytes(),
+        content_type: None,
+        expires_at: body.ttl_ms.map(|ttl| now_ms() + ttl),
+        context: None, // filled in at commit
+    };
+    if let Some(msg) = oversized(&state, &op) {
+        return too_large(&state, "PUT", "/txn/:id/key/:key", msg);
+    }
+    // a refused write isn't buffered; the transaction stays open with what it already holds
+    match state.txns.with(id, |txn| txn.buffer(key, op)) {
+        Some(Ok(())) => StatusCode::OK.into_response(),
+        Some(Err(msg)) => too_large(&state, "PUT", "/txn/:id/key/:key", msg),
+        None => unknown_txn(),
+    }
+}
+
+pub async fn txn_delete(
+    State(state): State<ApiState>,
+    Path((id, key)): Path<(u64, String)>,
+) -> Response {
+    if let Some(resp) = refuse_crdt(&state, &key, "DELETE", "/txn/:id/key/:key") {
+        return resp;
+    }
+    let op = Operation::Delete { key: key.clone(), context: None };
+    match state.txns.with(id, |txn| txn.buffer(key, op)) {
+        Some(Ok(())) => StatusCode::OK.into_response(),
+        Some(Err(msg)) => too_large(&state, "DELETE", "/txn/:id/key/:key", msg),
+        None => unknown_txn(),
+    }
+}
+
+// Commits if no key read by the transaction has changed since, writing all buffered writes as
+// one batch; otherwise answers 409 with the changed keys and writes nothing.
+pub async fn txn_commit(
+    State(state): State<ApiState>,
+    Path(id): Path<u64>,
+) -> Response {
+    let Some(txn) = state.txns.take(id) else {
+        return unknown_txn();
+    };
+
+    let checks: Vec<(String, Condition)> = txn.reads
+        .into_iter()
+        .map(|(key, version)| {
+            let condition = match version {
+                Some(v) => Condition::VersionIn(vec![v]),
+                None => Condition::Absent,
+            };
+            (key, condition)
+        })
+        .collect();
+
+    let outcome = if txn.writes.is_empty() {
+        // nothing to write, but the writer still checks every read at one point in the log
+        let conflicts = match state.wal_writer.check(checks).await {
+            Ok(conflicts) => conflicts,
+            Err(e) => {
+                eprintln!("Transaction check failed: {}", e);
+                state.metrics.errors.with_label_values(&["storage"]).inc();
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        if conflicts.is_empty() {
+            state.metrics.requests.with_label_values(&["POST", "/txn/:id/commit", "200"]).inc();
+            return (StatusCode::OK, Json(CommitResp { ts: None, node_id: None, written_at: None })).into_response();
+        }
+        CondOutcome::Failed { conflicts }
+    } else {
+        // with siblings each write replaces what the transaction read of its key
+        let siblings = state.store.keeps_siblings();
+        let mut contexts = txn.contexts;
+        let ops = txn.writes
+            .into_iter()
+            .map(|(key, mut op)| {
+                if let Operation::Put { context, .. } | Operation::Delete { context, .. } = &mut op {
+                    *context = siblings.then(|| contexts.remove(&key).unwrap_or_default());
+                }
+                op
+            })
+            .collect();
+        match commit_write(&state, Operation::Batch { ops }, checks, "POST", "/txn/:id/commit").await {
+            Ok(outcome) => outcome,
+            Err(resp) => return resp,
+        }
+    };
+
+    match outcome {
+        CondOutcome::Applied { entry, .. } => {
+            state.metrics.kv_ops.with_label_values(&["txn_commit"]).inc();
+            state.metrics.requests.with_label_values(&["POST", "/txn/:id/commit", "200"]).inc();
+            (StatusCode::OK, Json(CommitResp { ts: Some(entry.ts), node_id: Some(entry.node_id), written_at: state.clock.wall_ms(entry.ts) })).into_response()
+        }
+        CondOutcome::Failed { conflicts } => {
+            state.metrics.errors.with_label_values(&["txn_conflict"]).inc();
+            state.metrics.requests.with_label_values(&["POST", "/txn/:id/commit", "409"]).inc();
+            let conflicts: Vec<String> = conflicts.into_iter().map(|(key, _)| key).collect();
+            let error = format!("transaction aborted: {} changed since it was read; retry", conflicts.join(", "));
+            (StatusCode::CONFLICT, Json(ConflictResp { error, conflicts })).into_response()
+        }
+    }
+}
+
+pub async fn txn_abort(
+    State(state): State<ApiState>,
+    Path(id): Path<u64>,
+) -> Response {
+    match state.txns.take(id) {
+        Some(_) => StatusCode::OK.into_response(),
+        None => unknown_txn(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txn() -> Txn {
+        Txn { reads: HashMap::new(), writes: BTreeMap::new(), contexts: HashMap::new(), touched: Instant::now() }
+    }
+
+    fn put(key: &str, len: usize) -> Operation {
+        Operation::Put { key: key.to_string(), value: vec![b'v'; len], content_type: None, expires_at: None, context: None }
+    }
+
+    #[test]
+    fn caps_the_bytes_buffered_and_counts_a_rewrite_once() {
+        let mut txn = txn();
+        let half = MAX_WRITE_BYTES / 2;
+        txn.buffer("a".to_string(), put("a", half)).unwrap();
+        txn.buffer("a".to_string(), put("a", half)).unwrap();
+        assert!(txn.buffer("b".to_string(), put("b", half)).is_err());
+        // the refused write leaves what was buffered alone
+        assert_eq!(txn.writes.len(), 1);
+        txn.buffer("b".to_string(), put("b", 10)).unwrap();
+    }
+
+    #[test]
+    fn caps_the_number_of_writes() {
+        let mut txn = txn();
+        for i in 0..TXN_MAX_WRITES {
+            txn.buffer(format!("k{}", i), Operation::Delete { key: format!("k{}", i), context: None }).unwrap();
+        }
+        assert!(txn.buffer("one-more".to_string(), put("one-more", 1)).is_err());
+        txn.buffer("k0".to_string(), put("k0", 1)).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use std::convert::Infallible;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}},
+};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+use crate::api::ApiState;
+use crate::api::client::DataView;
+use crate::store::LamportClock;
+use crate::util::{LogEntry, Operation};
+
+// events buffered per watcher before it stops reading from the writer
+const WATCH_BUFFER: usize = 256;
+
+#[derive(Deserialize)]
+pub struct WatchQuery {
+    key: Option<String>,
+    prefix: Option<String>,
+    since: Option<u64>,    // replay events stamped after this Lamport ts before going live
+    since_ms: Option<u64>, // the same from a unix ms time, with the hybrid clock
+}
+
+enum Filter {
+    Key(String),
+    Prefix(String),
+}
+
+impl Filter {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Filter::Key(k) => key == k,
+            Filter::Prefix(p) => key.starts_with(p.as_str()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WatchEvent {
+    key: String,
+    #[serde(flatten)]
+    data: DataView,
+    ts: u64,
+    node_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+// One SSE event per matching write in the entry, named put, delete, or update for counters and
+// sets; the id is its Lamport ts. A batch yields one event per op it touches, all with the batch's ts.
+fn events_for(entry: &LogEntry, filter: &Filter, clock: &LamportClock) -> Vec<Event> {
+    let ops = match &entry.operation {
+        Operation::Batch { ops } => ops.iter().collect(),
+        op => vec![op],
+    };
+    ops.into_iter()
+        .filter_map(|op| {
+            let (name, event) = match op {
+                Operation::Put { key, value, content_type, expires_at, .. } => ("put", WatchEvent {
+                    key: key.clone(),
+                    data: DataView::new(Some(value.clone()), content_type.clone()),
+                    ts: entry.ts,
+                    node_id: entry.node_id,
+                    written_at: clock.wall_ms(entry.ts),
+                    expires_at: *expires_at,
+                }),
+                Operation::Delete { key, .. } => ("delete", WatchEvent {
+                    key: key.clone(),
+                    data: DataView::default(),
+                    ts: entry.ts,
+                    node_id: entry.node_id,
+                    written_at: clock.wall_ms(entry.ts),
+                    expires_at: None,
+                }),
+                // the event says the key changed; read it for the new count or members
+                Operation::Counter { key, .. } | Operation::Set { key, .. } => ("update", WatchEvent {
+                    key: key.clone(),
+                    data: DataView::default(),
+                    ts: entry.ts,
+                    node_id: entry.node_id,
+                    written_at: clock.wall_ms(entry.ts),
+                    expires_at: None,
+                }),
+                // purging a tombstone changes nothing a reader can see
+                Operation::Purge { .. } | Operation::Batch { .. } => return None,
+            };
+            filter.matches(&event.key).then(|| {
+                Event::default()
+                    .event(name)
+                    .id(entry.ts.to_string())
+                    .json_data(&event)
+                    .unwrap()
+            })
+        })
+        .collect()
+}
+
+// Streams puts and deletes on `key`, or on every key under `prefix`, as Server-Sent Events as
+// this node applies them, local writes and replicated ones alike. With `since`, entries still
+// in the WAL are replayed first; 410 means the WAL no longer reaches back that far and the
+// client should read the keys again and watch from there. With the hybrid clock `since_ms`
+// replays by wall time instead, e.g. everything written in the last five minutes. A watcher that falls too far
+// behind gets a `lagged` event and the stream ends; reconnect with the last id seen.
+pub async fn watch(
+    State(state): State<ApiState>,
+    Query(q): Query<WatchQuery>,
+) -> Response {
+    let filter = match (q.key, q.prefix) {
+        (Some(key), None) => Filter::Key(key),
+        (None, Some(prefix)) => Filter::Prefix(prefix),
+        _ => {
+            state.metrics.requests.with_label_values(&["GET", "/watch", "400"]).inc();
+            return (StatusCode::BAD_REQUEST, "pass exactly one of key or prefix").into_response();
+        }
+    };
+
+    let since = match (q.since, q.since_ms) {
+        (since, None) => since,
+        (None, Some(ms)) => match state.clock.ts_at(ms) {
+            Some(ts) => Some(ts.saturating_sub(1)),
+            None => {
+                state.metrics.requests.with_label_values(&["GET", "/watch", "400"]).inc();
+                return (StatusCode::BAD_REQUEST, "since_ms needs the hybrid clock").into_response();
+            }
+        },
+        (Some(_), Some(_)) => {
+            state.metrics.requests.with_label_values(&["GET", "/watch", "400"]).inc();
+            return (StatusCode::BAD_REQUEST, "pass at most one of since or since_ms").into_response();
+        }
+    };
+
+    // subscribing under the WAL lock means every entry is either in the backlog or the feed
+    let (backlog, mut feed) = {
+        let wal = state.wal.lock().await;
+        let feed = state.wal_writer.subscribe();
+        let backlog = match since {
+            Some(since) if since < wal.pruned_hw() => {
+                state.metrics.requests.with_label_values(&["GET", "/watch", "410"]).inc();
+                return (StatusCode::GONE, "events before that timestamp are no longer retained").into_response();
+            }
+            Some(since) => match wal.committed() {
+                Ok(log) => Some((since, log)),
+                Err(e) => {
+                    eprintln!("Reading the WAL for a watch failed: {}", e);
+                    state.metrics.errors.with_label_values(&["wal"]).inc();
+                    return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+                }
+            },
+            None => None,
+        };
+        (backlog, feed)
+    };
+
+    let (tx, rx) = mpsc::channel::<Event>(WATCH_BUFFER);
+    let clock = state.clock.clone();
+    let errors = state.metrics.errors.clone();
+    tokio::spawn(async move {
+        if let Some((since, log)) = backlog {
+            // the segments are read off the runtime, a buffer's worth of entries at a time
+            let (entries_tx, mut entries) = mpsc::channel::<LogEntry>(WATCH_BUFFER);
+            let reading = tokio::task::spawn_blocking(move || {
+                log.read_since(since, |entry| entries_tx.blocking_send(entry).is_ok())
+            });
+            while let Some(entry) = entries.recv().await {
+                for event in events_for(&entry, &filter, &clock) {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            match reading.await {
+                Ok(Ok(())) => {}
+                // e.g. compaction removed a segment meanwhile; the client reconnects from its last id
+                Ok(Err(e)) => {
+                    eprintln!("Reading the WAL for a watch failed: {}", e);
+                    errors.with_label_values(&["wal"]).inc();
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+        loop {
+            match feed.recv().await {
+                Ok(entry) => {
+                    for event in events_for(&entry, &filter, &clock) {
+                        if tx.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    let _ = tx.send(Event::default().event("lagged").data(missed.to_string())).await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+
+    state.metrics.requests.with_label_values(&["GET", "/watch", "200"]).inc();
+    let events = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok::<_, Infallible>(event), rx))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use futures::StreamExt;
+    use tokio::sync::{Mutex, RwLock};
+    use super::*;
+    use crate::api::{Metrics, TxnRegistry};
+    use crate::cluster::ClusterState;
+    use crate::store::{Durability, SnapshotCfg, Store, Wal, WalOptions, spawn_wal_writer};
+
+    fn put(ts: u64, key: &str) -> LogEntry {
+        let operation = Operation::Put { key: key.to_string(), value: b"v".to_vec(), content_type: None, expires_at: None, context: None };
+        LogEntry { ts, node_id: 1, operation }
+    }
+
+    fn query(since: u64) -> Query<WatchQuery> {
+        Query(WatchQuery { key: None, prefix: Some("a/".to_string()), since: Some(since), since_ms: None })
+    }
+
+    // the ids of the next `n` events on the stream
+    async fn ids(body: &mut (impl futures::Stream<Item = Result<axum::body::Bytes, axum::Error>> + Unpin), n: usize) -> Vec<u64> {
+        let mut ids = Vec::new();
+        while ids.len() < n {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+            let text = String::from_utf8(chunk.to_vec()).unwrap();
+            ids.extend(text.lines().filter_map(|line| line.strip_prefix("id: ")).map(|id| id.parse::<u64>().unwrap()));
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn replays_the_retained_backlog_then_goes_live() {
+        let dir = std::env::temp_dir().join(format!("kv-watch-since-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let opts = WalOptions { segment_bytes: 1 << 20, durability: Durability::Os, fsync_seconds: None, keys: None };
+        let wal = Arc::new(Mutex::new(Wal::open(&dir, opts, 1).unwrap()));
+        let store = Arc::new(Store::new());
+        let clock = Arc::new(LamportClock::new());
+        let wal_writer = spawn_wal_writer(Arc::clone(&wal), Arc::clone(&store), Arc::clone(&clock), 0);
+        wal_writer.submit(vec![put(1, "a/1"), put(2, "a/2"), put(3, "b"), put(4, "a/3")]).await.unwrap();
+        // as if a compaction removed the segment holding ts 1
+        wal.lock().await.mark_pruned(1);
+
+        let cluster = ClusterState { node_id: 1, address: String::new(), leader_id: 1, peer_addresses: Vec::new(), is_alive: HashMap::new() };
+        let state = ApiState {
+            store,
+            clock,
+            cluster: Arc::new(RwLock::new(cluster)),
+            metrics: Metrics::new(),
+            wal,
+            wal_writer: wal_writer.clone(),
+            rep_tx: mpsc::channel(1).0,
+            snapshot_cfg: SnapshotCfg {
+                dir: dir.join("snapshots").display().to_string(),
+                interval: Duration::from_secs(60),
+                compact_wal_bytes: 0,
+                compact_wal_entries: 0,
+                wal_retain_segments: 0,
+                keys: None,
+            },
+            txns: Arc::new(TxnRegistry::new()),
+            max_value_bytes: 1 << 20,
+            read_only_at: None,
+        };
+
+        assert_eq!(watch(State(state.clone()), query(0)).await.status(), StatusCode::GONE);
+
+        let response = watch(State(state), query(1)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        assert_eq!(ids(&mut body, 2).await, vec![2, 4]);
+        wal_writer.submit(vec![put(5, "b"), put(6, "a/4")]).await.unwrap();
+        assert_eq!(ids(&mut body, 1).await, vec![6]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
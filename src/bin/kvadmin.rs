@@ -0,0 +1,198 @@
+use std::{collections::{BTreeMap, HashSet}, path::PathBuf, process};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+
+use distributed_key_value_store::store::{Keyring, SegmentCheck, check_wal, repair_torn_tail};
+use distributed_key_value_store::util::{LogEntry, Operation};
+
+// Offline tooling for a node's files. Point it at a stopped node's WAL, or at a copy: truncate
+// rewrites the newest segment in place.
+#[derive(Parser)]
+#[command(name = "kvadmin")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(subcommand)]
+    Wal(WalCommand),
+}
+
+#[derive(Subcommand)]
+enum WalCommand {
+    // entries as JSON, one per line, with the segment and offset they were read at
+    Dump {
+        #[command(flatten)]
+        wal: WalArgs,
+        // only entries touching this key, batches included
+        #[arg(long)]
+        key: Option<String>,
+        // inclusive ts range
+        #[arg(long)]
+        from_ts: Option<u64>,
+        #[arg(long)]
+        to_ts: Option<u64>,
+    },
+    // reads every segment and reports where each bad one stops; exits 1 on corruption
+    Verify {
+        #[command(flatten)]
+        wal: WalArgs,
+    },
+    // cuts a torn record off the end of the newest segment, as node startup would
+    Truncate {
+        #[command(flatten)]
+        wal: WalArgs,
+    },
+    // entry counts per operation, key cardinality and the ts range
+    Stats {
+        #[command(flatten)]
+        wal: WalArgs,
+    },
+}
+
+#[derive(Args)]
+struct WalArgs {
+    // defaults to $WAL_DIR, then ./wal, like the node
+    #[arg(long)]
+    wal_dir: Option<String>,
+
+    // needed for an encrypted WAL; the same files the node was started with
+    #[arg(long)]
+    encryption_key_file: Option<PathBuf>,
+
+    #[arg(long, value_delimiter = ',', requires = "encryption_key_file")]
+    previous_key_files: Vec<PathBuf>,
+
+    // also read plaintext segments from before encryption was turned on
+    #[arg(long, requires = "encryption_key_file")]
+    accept_plaintext: bool,
+}
+
+impl WalArgs {
+    fn dir(&self) -> String {
+        self.wal_dir
+            .clone()
+            .unwrap_or_else(|| std::env::var("WAL_DIR").unwrap_or_else(|_| "wal".to_string()))
+    }
+
+    fn keys(&self) -> anyhow::Result<Option<Keyring>> {
+        match &self.encryption_key_file {
+            Some(path) => Ok(Some(Keyring::load(path, &self.previous_key_files)?.accepting_plaintext(self.accept_plaintext))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DumpLine {
+    segment: u64,
+    offset: u64,
+    #[serde(flatten)]
+    entry: LogEntry,
+}
+
+#[derive(Serialize, Default)]
+struct Stats {
+    segments: usize,
+    entries: u64,
+    ops: BTreeMap<&'static str, u64>, // a batch counts once, and so does each op in it
+    keys: usize,                      // distinct keys written or deleted
+    first_ts: Option<u64>,
+    last_ts: Option<u64>,
+    corrupt_segments: usize,
+}
+
+fn op_name(op: &Operation) -> &'static str {
+    match op {
+        Operation::Put { .. } => "put",
+        Operation::Delete { .. } => "delete",
+        Operation::Counter { .. } => "counter",
+        Operation::Set { .. } => "set",
+        Operation::Purge { .. } => "purge",
+        Operation::Batch { .. } => "batch",
+    }
+}
+
+// problems go to stderr so a dump's stdout stays valid JSON lines; true if any was corruption
+fn report_problems(checks: &[SegmentCheck]) -> bool {
+    let mut corrupt = false;
+    for check in checks {
+        if let Some(offset) = check.torn_at {
+            eprintln!("segment {}: torn record at offset {}, `kvadmin wal truncate` cuts it", check.segment, offset);
+        }
+        if let Some(error) = &check.error {
+            eprintln!("segment {}: {}", check.segment, error);
+            corrupt = true;
+        }
+    }
+    corrupt
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let Command::Wal(command) = cli.command;
+
+    match command {
+        WalCommand::Dump { wal, key, from_ts, to_ts } => {
+            let keys = wal.keys()?;
+            let checks = check_wal(wal.dir(), keys.as_ref(), |segment, offset, entry| {
+                let in_range = from_ts.is_none_or(|from| entry.ts >= from) && to_ts.is_none_or(|to| entry.ts <= to);
+                let has_key = key.as_deref().is_none_or(|k| entry.operation.keys().contains(&k));
+                if in_range && has_key {
+                    println!("{}", serde_json::to_string(&DumpLine { segment, offset, entry }).unwrap());
+                }
+            })?;
+            if report_problems(&checks) {
+                process::exit(1);
+            }
+        }
+        WalCommand::Verify { wal } => {
+            let keys = wal.keys()?;
+            let checks = check_wal(wal.dir(), keys.as_ref(), |_, _, _| {})?;
+            for check in &checks {
+                if check.error.is_none() && check.torn_at.is_none() {
+                    println!("segment {}: {} entries, ok", check.segment, check.entries);
+                } else {
+                    println!("segment {}: {} readable entries", check.segment, check.entries);
+                }
+            }
+            if report_problems(&checks) {
+                process::exit(1);
+            }
+        }
+        WalCommand::Truncate { wal } => {
+            let keys = wal.keys()?;
+            match repair_torn_tail(wal.dir(), keys.as_ref())? {
+                Some(pos) => println!("segment {} now ends at offset {}", pos.segment, pos.offset),
+                None => println!("no torn record at the tail"),
+            }
+        }
+        WalCommand::Stats { wal } => {
+            let keys = wal.keys()?;
+            let mut stats = Stats::default();
+            let mut seen = HashSet::new();
+            let checks = check_wal(wal.dir(), keys.as_ref(), |_, _, entry| {
+                stats.entries += 1;
+                stats.first_ts = Some(stats.first_ts.map_or(entry.ts, |ts| ts.min(entry.ts)));
+                stats.last_ts = Some(stats.last_ts.map_or(entry.ts, |ts| ts.max(entry.ts)));
+                *stats.ops.entry(op_name(&entry.operation)).or_default() += 1;
+                if let Operation::Batch { ops } = &entry.operation {
+                    for op in ops {
+                        *stats.ops.entry(op_name(op)).or_default() += 1;
+                    }
+                }
+                seen.extend(entry.operation.keys().into_iter().map(str::to_string));
+            })?;
+            stats.segments = checks.len();
+            stats.keys = seen.len();
+            stats.corrupt_segments = checks.iter().filter(|c| c.error.is_some()).count();
+            println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+            if report_problems(&checks) {
+                process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
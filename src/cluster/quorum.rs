@@ -1,6 +1,7 @@
 use std::time::Duration;
 use tokio::time::timeout;
 use reqwest::Client;
+use base64::{Engine, engine::general_purpose::STANDARD};
 use futures::stream::{FuturesUnordered, StreamExt};
 
 use crate::util::LogEntry;
@@ -79,14 +80,20 @@ pub async fn quorum_read(
         tasks.push(async move {
             match timeout(Duration::from_secs(2), client_clone.get(&url).send()).await {
                 Ok(Ok(resp)) if resp.status().is_success() => {
-                    if let Ok(json) = resp.json::<serde_json::Value>().await {
-                        if let (Some(ts), Some(node_id)) = (
+                    if let Ok(json) = resp.json::<serde_json::Value>().await
+                        && let (Some(ts), Some(node_id)) = (
                             json.get("ts").and_then(|v| v.as_u64()),
                             json.get("node_id").and_then(|v| v.as_u64()),
-                        ) {
-                            let data = json.get("data").and_then(|v| v.as_str()).map(|s| s.to_string());
-                            return Some(Value { data, ts, node_id });
-                        }
+                        )
+                    {
+                        // text comes in "data", other bytes base64-encoded in "data_b64"
+                        let data = match json.get("data_b64").and_then(|v| v.as_str()) {
+                            Some(b64) => STANDARD.decode(b64).ok(),
+                            None => json.get("data").and_then(|v| v.as_str()).map(|s| s.as_bytes().to_vec()),
+                        };
+                        let content_type = json.get("content_type").and_then(|v| v.as_str()).map(|s| s.to_string());
+                        let expires_at = json.get("expires_at").and_then(|v| v.as_u64());
+                        return Some(Value { data, content_type, expires_at, ..Value::tombstone(ts, node_id) });
                     }
                 }
                 _ => {}
@@ -23,10 +23,10 @@ impl From<CliArgs> for ClusterState {
 
         ClusterState {
             node_id: args.node_id,
-            address: address,
+            address,
             leader_id: args.leader_id,
             peer_addresses: peers,
-            is_alive: is_alive,
+            is_alive,
         }
     }
 }
\ No newline at end of file
@@ -1,6 +1,11 @@
-use clap::Parser;
+use std::path::PathBuf;
 
-#[derive(Parser, Debug)]
+use clap::{Parser, ValueEnum};
+
+use crate::store::IndexDef;
+use crate::util::MAX_WRITE_BYTES;
+
+#[derive(Parser, Debug, Clone)]
 pub struct CliArgs {
     #[arg(long)]
     pub node_id: u64,
@@ -12,5 +17,131 @@ pub struct CliArgs {
     pub leader_id: u64,
 
     #[arg(long, value_delimiter = ',')]
-    pub peer_addresses: Vec<String>
-}
\ No newline at end of file
+    pub peer_addresses: Vec<String>,
+
+    #[arg(long, value_enum, default_value_t = EngineKind::Memory)]
+    pub engine: EngineKind,
+
+    // use the same clock on every node of a cluster
+    #[arg(long, value_enum, default_value_t = ClockKind::Lamport)]
+    pub clock: ClockKind,
+
+    #[arg(long, value_enum, default_value_t = ConflictMode::Lww)]
+    pub conflicts: ConflictMode,
+
+    // where the sled engine keeps its database
+    #[arg(long, default_value = "data")]
+    pub data_dir: String,
+
+    #[arg(long, default_value = "snapshots")]
+    pub snapshot_dir: String,
+
+    #[arg(long, default_value_t = 60)]
+    pub snapshot_interval_secs: u64,
+
+    // 0 disables the threshold
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    pub compact_wal_bytes: u64,
+
+    #[arg(long, default_value_t = 100_000)]
+    pub compact_wal_entries: u64,
+
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    pub wal_segment_bytes: u64,
+
+    // segments already covered by a snapshot that are kept anyway
+    #[arg(long, default_value_t = 0)]
+    pub wal_retain_segments: u64,
+
+    // how long a tombstone is kept before the leader may purge it
+    #[arg(long, default_value_t = 3600)]
+    pub tombstone_grace_secs: u64,
+
+    // longest value a write may carry, or an append or get-and-set leave behind
+    #[arg(long, default_value_t = 1024 * 1024, value_parser = clap::value_parser!(u64).range(1..=MAX_WRITE_BYTES as u64))]
+    pub max_value_bytes: u64,
+
+    // versions kept per key, the latest included, for ?as_of reads and /history
+    #[arg(long, default_value_t = 1)]
+    pub versions_retained: usize,
+
+    // secondary index over a JSON field as name=prefix:field.path; repeat for more
+    #[arg(long = "index")]
+    pub indexes: Vec<IndexDef>,
+
+    // encrypt WAL records and snapshots with the base64 key in this file
+    #[arg(long)]
+    pub encryption_key_file: Option<PathBuf>,
+
+    // keys replaced by a rotation, still needed to read what they sealed until the next compaction
+    #[arg(long, value_delimiter = ',', requires = "encryption_key_file")]
+    pub previous_key_files: Vec<PathBuf>,
+
+    // read plaintext WAL segments and snapshots left from before encryption was turned on;
+    // compaction seals the ones it keeps, after which this can be dropped
+    #[arg(long, requires = "encryption_key_file")]
+    pub accept_plaintext: bool,
+
+    // point-in-time recovery: rebuild the state as of this timestamp from the snapshots and
+    // WAL, then serve it read-only, or write it to --recover-into and exit
+    #[arg(long, conflicts_with = "recover_to_ms")]
+    pub recover_to_ts: Option<u64>,
+
+    // the same as of a wall-clock time in unix ms (needs --clock hybrid)
+    #[arg(long)]
+    pub recover_to_ms: Option<u64>,
+
+    // new snapshot directory for the recovered state; start a node on it with an empty WAL_DIR
+    #[arg(long)]
+    pub recover_into: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = DurabilityMode::Always)]
+    pub wal_durability: DurabilityMode,
+
+    // only used with --wal-durability interval
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    pub wal_fsync_interval_ms: u64,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum DurabilityMode {
+    Always,   // fsync every write
+    Interval, // fsync every --wal-fsync-interval-ms
+    Os,       // leave flushing to the OS
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum EngineKind {
+    Memory, // in RAM, rebuilt from snapshot + WAL on restart
+    Sled,   // on disk under --data-dir, only the WAL tail is replayed
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ClockKind {
+    Lamport, // plain counter
+    Hybrid,  // hybrid logical clock: timestamps carry wall time (ms << 16 | counter)
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ConflictMode {
+    Lww,      // the write with the highest (ts, node_id) wins
+    Siblings, // concurrent writes are kept and returned together until a write resolves them
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &[&str]) -> Result<CliArgs, clap::Error> {
+        let base = ["node", "--node-id", "1", "--address", "http://127.0.0.1:3000", "--leader-id", "1"];
+        CliArgs::try_parse_from(base.iter().chain(extra))
+    }
+
+    #[test]
+    fn refuses_a_zero_fsync_interval() {
+        assert!(parse(&["--wal-durability", "interval", "--wal-fsync-interval-ms", "0"]).is_err());
+        let args = parse(&["--wal-durability", "interval", "--wal-fsync-interval-ms", "5"]).unwrap();
+        assert!(matches!(args.wal_durability, DurabilityMode::Interval));
+        assert_eq!(args.wal_fsync_interval_ms, 5);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod api;
+pub mod cluster;
+pub mod config;
+pub mod replication;
+pub mod store;
+pub mod util;
@@ -1,21 +1,15 @@
-mod api;
-mod cluster;
-mod store;
-mod config;
-mod util;
-mod replication;
-
-use std::{collections::HashMap, time::Duration, env, sync::Arc, net::SocketAddr};
+use std::{collections::HashMap, time::Duration, env, sync::Arc, net::SocketAddr, path::Path};
 use axum::serve;
 use tokio::{time::{interval, MissedTickBehavior}, sync::{mpsc, RwLock, Mutex}, net::TcpListener};
 use clap::Parser;
 use reqwest::Client;
 
-use crate::api::{ApiState, Metrics, RouterBuilder};
-use crate::cluster::ClusterState;
-use crate::config::CliArgs;
-use crate::store::{Store, LamportClock, Wal, recover_from_snapshot_and_wal};
-use crate::replication::{spawn_leader_replicator};
+use distributed_key_value_store::api::{ApiState, Metrics, RouterBuilder, TxnRegistry, spawn_expiry_reaper, spawn_tombstone_gc};
+use distributed_key_value_store::cluster::ClusterState;
+use distributed_key_value_store::config::{CliArgs, ClockKind, ConflictMode, DurabilityMode, EngineKind};
+use distributed_key_value_store::store::{Store, SledEngine, LamportClock, Wal, WalOptions, Durability, Keyring, SnapshotCfg, migrate_legacy_wal, recover_from_snapshot_and_wal, recover_to, export_snapshot, spawn_snapshotter, spawn_wal_writer};
+use distributed_key_value_store::replication::{spawn_leader_replicator};
+use distributed_key_value_store::util::LogEntry;
 
 // Testing chaos configuration
 #[derive(Clone, Debug)]
@@ -74,24 +68,111 @@ fn spawn_heartbeat(cluster: Arc<RwLock<ClusterState>>) {
 async fn main() -> anyhow::Result<()> {
     let args = CliArgs::parse();
 
-    // Assemble core state
-    let mut store = Store::new();
-    let clock = LamportClock::new();
-    let cluster = Arc::new(RwLock::new(ClusterState::from(args)));
+    let clock = match args.clock {
+        ClockKind::Lamport => LamportClock::new(),
+        ClockKind::Hybrid => LamportClock::hybrid(),
+    };
+    // point-in-time recovery target: the last timestamp to keep
+    let recover_until = match (args.recover_to_ts, args.recover_to_ms) {
+        (Some(ts), _) => Some(ts),
+        (None, Some(ms)) => match clock.ts_at(ms + 1) {
+            Some(ts) => Some(ts - 1),
+            None => anyhow::bail!("--recover-to-ms needs --clock hybrid"),
+        },
+        (None, None) => None,
+    };
+    if recover_until.is_none() && args.recover_into.is_some() {
+        anyhow::bail!("--recover-into needs --recover-to-ts or --recover-to-ms");
+    }
+
+    // sled keeps its own copy of every value, which nothing here encrypts
+    if matches!(args.engine, EngineKind::Sled) && args.encryption_key_file.is_some() && recover_until.is_none() {
+        anyhow::bail!("--encryption-key-file only covers the WAL and snapshots; use it with --engine memory");
+    }
+
+    // Assemble core state. Point-in-time recovery rebuilds in memory and leaves the
+    // engine's own data alone.
+    let store = match args.engine {
+        _ if recover_until.is_some() => Store::new(),
+        EngineKind::Memory => Store::new(),
+        EngineKind::Sled => Store::with_engine(Box::new(SledEngine::open(&args.data_dir)?)),
+    }
+    .retain_versions(args.versions_retained)
+    .with_indexes(args.indexes.clone());
+    let store = match args.conflicts {
+        ConflictMode::Lww => store,
+        ConflictMode::Siblings if args.versions_retained > 1 => {
+            anyhow::bail!("--versions-retained only applies with --conflicts lww");
+        }
+        ConflictMode::Siblings => store.keep_siblings(),
+    };
+    let cluster = Arc::new(RwLock::new(ClusterState::from(args.clone())));
+
+    let keys = match &args.encryption_key_file {
+        Some(path) => Some(Keyring::load(path, &args.previous_key_files)?.accepting_plaintext(args.accept_plaintext)),
+        None => None,
+    };
 
     // Recover BEFORE wrapping in Arc
-    let snapshot_path = "snapshots/latest.snap"; // UNUSED
-    let wal_path = std::env::var("WAL_PATH").unwrap_or_else(|_| "wal.log".to_string());
-    recover_from_snapshot_and_wal(&mut store, &clock, snapshot_path, &wal_path).await?;
+    let wal_dir = std::env::var("WAL_DIR").unwrap_or_else(|_| "wal".to_string());
 
-    println!("Recovered store state: {:#?}", store);
+    // a log left by a version from before WAL segments
+    let legacy_wal = std::env::var("WAL_PATH").ok();
+    match legacy_wal.as_deref().unwrap_or("wal.log") {
+        path if Path::new(path).is_file() && recover_until.is_some() => {
+            anyhow::bail!("{} is a single-file WAL; start the node normally once to move it into WAL_DIR", path);
+        }
+        path if Path::new(path).is_file() => {
+            let moved = migrate_legacy_wal(path, &wal_dir, keys.as_ref())?;
+            println!("Moved {} entries from {} into WAL segment 1 in {}", moved, path, wal_dir);
+        }
+        path if legacy_wal.is_some() => {
+            anyhow::bail!("WAL_PATH is set but {} doesn't exist; the WAL is now the directory in WAL_DIR", path);
+        }
+        _ => {}
+    }
+    let recovered = match recover_until {
+        Some(ts) => recover_to(&store, &clock, &args.snapshot_dir, &wal_dir, keys.as_ref(), ts).await?,
+        None => recover_from_snapshot_and_wal(&store, &clock, &args.snapshot_dir, &wal_dir, keys.as_ref()).await?,
+    };
+    if let Some(dir) = &args.recover_into {
+        let path = export_snapshot(&store, &clock, dir, keys.as_ref())?;
+        println!(
+            "Wrote recovered state to {}; start a node with --snapshot-dir {} and an empty WAL_DIR",
+            path.display(), dir
+        );
+        return Ok(());
+    }
 
     // Wrap recovered store + open WAL for runtime appends
     let store = Arc::new(store);
     let clock = Arc::new(clock);
-    let wal = Arc::new(Mutex::new(Wal::open(&wal_path)?));
-    let (rep_tx, rep_rx) = mpsc::channel::<util::LogEntry>(4096);
+    let metrics = Metrics::new();
+    let durability = match args.wal_durability {
+        DurabilityMode::Always => Durability::Always,
+        DurabilityMode::Interval => Durability::Interval(Duration::from_millis(args.wal_fsync_interval_ms)),
+        DurabilityMode::Os => Durability::Os,
+    };
+    metrics.wal_durability.with_label_values(&[durability.label()]).set(1);
+    let wal_opts = WalOptions {
+        segment_bytes: args.wal_segment_bytes,
+        durability,
+        fsync_seconds: Some(metrics.wal_fsync_seconds.clone()),
+        keys: keys.clone(),
+    };
+    // a recovered node must leave the WAL directory exactly as it found it
+    let mut wal = match recover_until {
+        Some(_) => Wal::open_read_only(&wal_dir, wal_opts)?,
+        None => Wal::open(&wal_dir, wal_opts, recovered.wal_position.segment)?,
+    };
+    if recover_until.is_none() && wal.is_trimmed()? {
+        // whatever was in the removed segments is covered by the checkpoint we resumed from
+        wal.mark_pruned(recovered.lamport_hw);
+    }
+    let wal = Arc::new(Mutex::new(wal));
+    let (rep_tx, rep_rx) = mpsc::channel::<LogEntry>(4096);
     let chaos = ChaosCfg::from_env();
+    let wal_writer = spawn_wal_writer(Arc::clone(&wal), Arc::clone(&store), Arc::clone(&clock), chaos.before_sync_ms);
 
     let leader_id = { cluster.read().await.leader_id };
     let node_id = { cluster.read().await.node_id };
@@ -102,25 +183,52 @@ async fn main() -> anyhow::Result<()> {
         drop(rep_rx); // non-leaders don't replicate
     }
 
+    let snapshot_cfg = SnapshotCfg {
+        dir: args.snapshot_dir.clone(),
+        interval: Duration::from_secs(args.snapshot_interval_secs),
+        compact_wal_bytes: args.compact_wal_bytes,
+        compact_wal_entries: args.compact_wal_entries,
+        wal_retain_segments: args.wal_retain_segments,
+        keys,
+    };
+
     // Assemble API state
     let state = ApiState { 
         store: Arc::clone(&store), 
         clock: Arc::clone(&clock), 
         cluster: Arc::clone(&cluster),
-        metrics: Metrics::new(),
+        metrics,
         wal: Arc::clone(&wal),
-        rep_tx: rep_tx,
-        chaos_before_sync_ms: chaos.before_sync_ms,
+        wal_writer,
+        rep_tx,
+        snapshot_cfg: snapshot_cfg.clone(),
+        txns: Arc::new(TxnRegistry::new()),
+        max_value_bytes: args.max_value_bytes as usize,
+        read_only_at: recover_until,
     };
 
     // Heartbeat
     spawn_heartbeat(Arc::clone(&cluster));
 
+    // a recovered node only serves reads: nothing may write to its WAL or snapshot directory
+    if recover_until.is_none() {
+        // Snapshots
+        spawn_snapshotter(Arc::clone(&store), Arc::clone(&clock), Arc::clone(&wal), snapshot_cfg);
+
+        // TTL expiry
+        spawn_expiry_reaper(state.clone());
+
+        // Tombstone GC
+        spawn_tombstone_gc(state.clone(), Duration::from_secs(args.tombstone_grace_secs));
+    }
+
     // Startup
     let c = cluster.read().await;
     println!(
-        "Node starting: node_id={}, listen_addr={}, wal_path={}, chaos_before_sync_ms={}",
-        c.node_id, c.address, wal_path, chaos.before_sync_ms
+        "Node starting: node_id={}, listen_addr={}, engine={:?}, clock={:?}, conflicts={:?}, indexes={:?}, wal_dir={}, wal_durability={:?}, snapshot_dir={}, encryption={:?}, read_only_at={:?}, chaos_before_sync_ms={}",
+        c.node_id, c.address, args.engine, args.clock, args.conflicts,
+        state.store.indexes().map(|i| i.name.as_str()).collect::<Vec<_>>(), wal_dir, durability, args.snapshot_dir,
+        state.snapshot_cfg.keys, state.read_only_at, chaos.before_sync_ms
     );
     drop(c);
 
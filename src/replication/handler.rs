@@ -6,10 +6,13 @@ use reqwest::Client;
 use serde::{Serialize, Deserialize};
 
 use crate::cluster::ClusterState;
-use crate::util::LogEntry;
+use crate::util::{LogEntry, MAX_WRITE_BYTES};
 
 const BATCH_MAX: usize = 128;
 const FLUSH_MS: u64 = 500;
+// What /replicate accepts: the largest write a node takes, with its values base64-encoded
+// (4 bytes per 3) and room for the JSON around them. Batches are split to fit.
+pub const REPLICATE_BODY_LIMIT: usize = MAX_WRITE_BYTES / 3 * 4 + 1024 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReplicateBody {
@@ -81,25 +84,74 @@ async fn peer_worker(client: Client, cluster: Arc<RwLock<ClusterState>>, peer_ba
     }
 }
 
-// sends the batch to the peer and clears it on success
+// Sends the batch to the peer in bodies within REPLICATE_BODY_LIMIT and clears what it took.
+// A body the peer refuses with a 4xx is sent again one entry at a time, and an entry refused
+// on its own is dropped: sending it again would only be refused again and hold up the rest.
 async fn flush(client: &Client, url: &str, batch: &mut Vec<LogEntry>) {
-    if batch.is_empty() { return; }
-    let body = ReplicateBody { entries: batch.clone() };
-    let resp = client.post(url)
-        .json(&body)
-        .send()
-        .await;
+    let mut one_at_a_time = false;
+    while !batch.is_empty() {
+        let take = if one_at_a_time { 1 } else { fitting(batch) };
+        let body = ReplicateBody { entries: batch[..take].to_vec() };
+        let resp = client.post(url)
+            .json(&body)
+            .send()
+            .await;
 
-    match resp {
-        Ok(r) => {
-            if !r.status().is_success() {
+        match resp {
+            Ok(r) if r.status().is_success() => {
+                batch.drain(..take);
+            }
+            Ok(r) if r.status().is_client_error() && take > 1 => {
+                one_at_a_time = true;
+            }
+            Ok(r) if r.status().is_client_error() => {
+                let entry = &batch[0];
+                eprintln!(
+                    "Replication to {} refused entry ts={} node_id={} with status {}; dropping it",
+                    url, entry.ts, entry.node_id, r.status()
+                );
+                batch.remove(0);
+            }
+            Ok(r) => {
                 eprintln!("Replication to {} failed with status: {}", url, r.status());
-            } else {
-                batch.clear();
+                return;
+            }
+            Err(e) => {
+                eprintln!("Replication to {} failed: {}", url, e);
+                return;
             }
         }
-        Err(e) => {
-            eprintln!("Replication to {} failed: {}", url, e);
+    }
+}
+
+// how many entries from the front of the batch fit one body, at least one
+fn fitting(batch: &[LogEntry]) -> usize {
+    let mut bytes = 0;
+    for (i, entry) in batch.iter().enumerate() {
+        bytes += serde_json::to_vec(entry).map_or(0, |json| json.len() + 1);
+        if bytes > REPLICATE_BODY_LIMIT {
+            return i.max(1);
         }
     }
-}
\ No newline at end of file
+    batch.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Operation;
+
+    fn put(len: usize) -> LogEntry {
+        let operation = Operation::Put { key: "k".to_string(), value: vec![0; len], content_type: None, expires_at: None, context: None };
+        LogEntry { ts: 1, node_id: 1, operation }
+    }
+
+    #[test]
+    fn splits_batches_to_fit_a_body_and_always_sends_one() {
+        let half = MAX_WRITE_BYTES / 2;
+        assert_eq!(fitting(&[put(1), put(1), put(1)]), 3);
+        assert_eq!(fitting(&[put(half), put(half), put(half)]), 2);
+        assert_eq!(fitting(&[put(MAX_WRITE_BYTES), put(MAX_WRITE_BYTES)]), 1);
+        assert_eq!(fitting(&[put(REPLICATE_BODY_LIMIT)]), 1);
+    }
+}
@@ -1,3 +1,3 @@
 pub mod handler;
 
-pub use handler::{ReplicateBody, spawn_leader_replicator};
\ No newline at end of file
+pub use handler::{ReplicateBody, REPLICATE_BODY_LIMIT, spawn_leader_replicator};
\ No newline at end of file
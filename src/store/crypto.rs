@@ -0,0 +1,186 @@
+use std::{fmt, fs, path::{Path, PathBuf}};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce, KeyInit,
+    aead::{Aead, AeadCore, OsRng, Payload},
+};
+
+// Authenticated encryption for what the node writes to disk. A sealed blob is
+//   [key id: u32 LE][nonce: 24 bytes][XChaCha20-Poly1305 ciphertext + tag]
+// with associated data naming where the blob lives, so a record or file copied elsewhere
+// fails to open just like a modified one. Nonces are random, which the extended nonce makes
+// safe for any number of records under one key.
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+pub const SEALED_OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OpenError {
+    #[error("sealed data is too short")]
+    Truncated,
+    #[error("sealed with key {0:08x}, which is not loaded")]
+    UnknownKey(u32),
+    #[error("failed authentication: wrong key or tampered data")]
+    Tampered,
+}
+
+#[derive(Clone)]
+struct Key {
+    id: u32,
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    // A key file holds 32 random bytes, base64 encoded (e.g. `openssl rand -base64 32`).
+    // Its id is a check value: the start of the tag an empty message gets under a zero nonce.
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("reading key file {}: {}", path.display(), e))?;
+        let bytes = B64
+            .decode(text.trim())
+            .map_err(|e| anyhow::anyhow!("key file {} is not base64: {}", path.display(), e))?;
+        if bytes.len() != KEY_LEN {
+            anyhow::bail!("key file {} holds {} bytes, expected {}", path.display(), bytes.len(), KEY_LEN);
+        }
+        let cipher = XChaCha20Poly1305::new_from_slice(&bytes).unwrap();
+        let check = cipher.encrypt(&XNonce::default(), &[][..]).unwrap();
+        let id = u32::from_le_bytes(check[..KEY_ID_LEN].try_into().unwrap());
+        Ok(Key { id, cipher })
+    }
+}
+
+// The key new data is sealed with, plus older keys that data written before a rotation may
+// still need. Compaction re-seals everything under the current key, after which the older
+// keys can be dropped.
+#[derive(Clone)]
+pub struct Keyring {
+    current: Key,
+    previous: Vec<Key>,
+    accept_plaintext: bool, // read data written before encryption was turned on
+}
+
+impl fmt::Debug for Keyring {
+    // ids only, never key material
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &format_args!("{:08x}", self.current.id))
+            .field("previous", &self.previous.iter().map(|k| format!("{:08x}", k.id)).collect::<Vec<_>>())
+            .field("accept_plaintext", &self.accept_plaintext)
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn load(current: &Path, previous: &[PathBuf]) -> anyhow::Result<Self> {
+        let current = Key::load(current)?;
+        let previous = previous.iter().map(|p| Key::load(p)).collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Keyring { current, previous, accept_plaintext: false })
+    }
+
+    // Plaintext found where sealed data is expected is refused unless this is set, so files
+    // swapped in by someone without the key aren't read as genuine. Set it while migrating a
+    // node that ran without encryption; compaction then seals what it still keeps.
+    pub fn accepting_plaintext(mut self, accept: bool) -> Self {
+        self.accept_plaintext = accept;
+        self
+    }
+
+    pub fn accepts_plaintext(&self) -> bool {
+        self.accept_plaintext
+    }
+
+    pub fn current_id(&self) -> u32 {
+        self.current.id
+    }
+
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.current.cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).unwrap();
+        let mut sealed = Vec::with_capacity(SEALED_OVERHEAD + plaintext.len());
+        sealed.extend_from_slice(&self.current.id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, OpenError> {
+        let id = sealed_key_id(sealed).ok_or(OpenError::Truncated)?;
+        if sealed.len() < SEALED_OVERHEAD {
+            return Err(OpenError::Truncated);
+        }
+        let key = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|k| k.id == id)
+            .ok_or(OpenError::UnknownKey(id))?;
+        let nonce = XNonce::from_slice(&sealed[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+        let msg = &sealed[KEY_ID_LEN + NONCE_LEN..];
+        key.cipher.decrypt(nonce, Payload { msg, aad }).map_err(|_| OpenError::Tampered)
+    }
+}
+
+// id of the key a blob was sealed with
+pub fn sealed_key_id(sealed: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(sealed.get(..KEY_ID_LEN)?.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_file(name: &str, byte: u8) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kv-key-{}-{}", name, std::process::id()));
+        fs::write(&path, B64.encode([byte; KEY_LEN])).unwrap();
+        path
+    }
+
+    #[test]
+    fn round_trips_under_the_same_aad() {
+        let path = key_file("round-trip", 1);
+        let keys = Keyring::load(&path, &[]).unwrap();
+        let sealed = keys.seal(b"hello", b"segment 1");
+
+        assert_eq!(sealed_key_id(&sealed), Some(keys.current_id()));
+        assert_eq!(keys.open(&sealed, b"segment 1").unwrap(), b"hello");
+        assert!(matches!(keys.open(&sealed, b"segment 2"), Err(OpenError::Tampered)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn detects_tampering_and_truncation() {
+        let path = key_file("tamper", 2);
+        let keys = Keyring::load(&path, &[]).unwrap();
+        let mut sealed = keys.seal(b"hello", b"");
+
+        assert!(matches!(keys.open(&sealed[..SEALED_OVERHEAD - 1], b""), Err(OpenError::Truncated)));
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(matches!(keys.open(&sealed, b""), Err(OpenError::Tampered)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn previous_keys_still_open_what_they_sealed() {
+        let (old, new) = (key_file("old", 3), key_file("new", 4));
+        let before = Keyring::load(&old, &[]).unwrap();
+        let sealed = before.seal(b"hello", b"");
+
+        let rotated = Keyring::load(&new, std::slice::from_ref(&old)).unwrap();
+        assert_ne!(rotated.current_id(), before.current_id());
+        assert_eq!(rotated.open(&sealed, b"").unwrap(), b"hello");
+        let dropped = Keyring::load(&new, &[]).unwrap();
+        assert!(matches!(dropped.open(&sealed, b""), Err(OpenError::UnknownKey(id)) if id == before.current_id()));
+        fs::remove_file(old).unwrap();
+        fs::remove_file(new).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_key_of_the_wrong_length() {
+        let path = std::env::temp_dir().join(format!("kv-key-short-{}", std::process::id()));
+        fs::write(&path, B64.encode([0u8; 16])).unwrap();
+        assert!(Keyring::load(&path, &[]).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,68 +1,887 @@
-use std::collections::HashMap;
-use tokio::sync::RwLock;
+use std::{collections::{BTreeSet, HashMap}, ops::Bound, sync::Mutex};
+use serde::{Serialize, Deserialize};
+use crate::store::{IndexDef, MemoryEngine, SecondaryIndex, WalPosition};
+use crate::util::{Crdt, LogEntry, Operation, PnCounter, VersionVector, now_ms};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Value {
-    pub data: Option<String>,
+    #[serde(default, with = "crate::util::blob::option")]
+    pub data: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>, // as given by the writer, returned with raw reads
     pub ts: u64,
     pub node_id: u64, // for tie-breaking in Lamport clocks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>, // unix ms after which the value reads as deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<u64>, // unix ms this node stored the tombstone, for GC; not compared
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Value>, // older retained versions, newest first; only set on stored records
+    #[serde(default, skip_serializing_if = "VersionVector::is_empty")]
+    pub context: VersionVector, // what the write had seen, with --conflicts siblings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub siblings: Vec<Value>, // concurrent versions besides this one, with --conflicts siblings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crdt: Option<Box<Crdt>>, // counter or set state; `data` then holds it rendered for plain reads
 }
 
 impl Value {
+    pub fn tombstone(ts: u64, node_id: u64) -> Self {
+        Value {
+            data: None,
+            content_type: None,
+            ts,
+            node_id,
+            expires_at: None,
+            deleted_at: None,
+            history: Vec::new(),
+            context: VersionVector::new(),
+            siblings: Vec::new(),
+            crdt: None,
+        }
+    }
+
+    // A counter or set, stamped with the newest write merged into it. Once deleted it is a
+    // tombstone that keeps the state, so the adds and increments the delete saw stay deleted
+    // whenever their copies arrive, until GC purges it.
+    fn of_crdt(crdt: Crdt, ts: u64, node_id: u64) -> Self {
+        if crdt.is_deleted() {
+            return Value { deleted_at: Some(now_ms()), crdt: Some(Box::new(crdt)), ..Value::tombstone(ts, node_id) };
+        }
+        Value {
+            data: Some(crdt.render()),
+            content_type: Some("application/json".to_string()),
+            crdt: Some(Box::new(crdt)),
+            ..Value::tombstone(ts, node_id)
+        }
+    }
+
+    fn version(&self) -> (u64, u64) {
+        (self.ts, self.node_id)
+    }
+
     pub fn is_newer_than(&self, other: Option<&Value>) -> bool {
-        match other {
-            None => true,
-            Some(v) => (self.ts > v.ts) || (self.ts == v.ts && self.node_id > v.node_id),
+        other.is_none_or(|v| self.version() > v.version())
+    }
+
+    pub fn without_history(mut self) -> Self {
+        self.history = Vec::new();
+        self
+    }
+
+    // the record followed by its history, newest first
+    fn into_versions(mut self) -> Vec<Value> {
+        let history = std::mem::take(&mut self.history);
+        std::iter::once(self).chain(history).collect()
+    }
+
+    // whether this version's write had seen `other`, and so replaces it
+    fn supersedes(&self, other: &Value) -> bool {
+        self.context.get(&other.node_id).is_some_and(|&ts| ts >= other.ts)
+    }
+
+    // the context a write replacing this version must carry
+    pub fn clock(&self) -> VersionVector {
+        let mut clock = self.context.clone();
+        let seen = clock.entry(self.node_id).or_default();
+        *seen = (*seen).max(self.ts);
+        clock
+    }
+
+    // Context for a write replacing the whole record: everything the record and its siblings
+    // have seen. Clients get it with reads and send it back with the write.
+    pub fn causal_context(&self) -> VersionVector {
+        let mut context = VersionVector::new();
+        for v in std::iter::once(self).chain(&self.siblings) {
+            for (node, ts) in v.clock() {
+                let seen = context.entry(node).or_default();
+                *seen = (*seen).max(ts);
+            }
         }
+        context
+    }
+
+    // the record and its siblings
+    pub fn into_siblings(mut self) -> Vec<Value> {
+        let siblings = std::mem::take(&mut self.siblings);
+        std::iter::once(self).chain(siblings).collect()
+    }
+
+    // Builds a record from concurrent versions. The newest one holding data leads, so a
+    // record only reads as deleted once every sibling is a tombstone.
+    fn from_siblings(mut siblings: Vec<Value>) -> Value {
+        siblings.sort_by_key(|v| std::cmp::Reverse((v.data.is_some(), v.version())));
+        let mut siblings = siblings.into_iter();
+        let mut head = siblings.next().unwrap();
+        head.siblings = siblings.collect();
+        head
+    }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_ms)
+    }
+
+    // holds data that has not expired yet
+    pub fn is_live(&self, now_ms: u64) -> bool {
+        self.data.is_some() && !self.is_expired(now_ms)
+    }
+}
+
+// how far the WAL is reflected in persisted state
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub lamport_hw: u64,
+    pub wal_position: WalPosition,
+}
+
+// what an update does with the record it read
+pub enum Update {
+    Keep,
+    Set(Value),
+    Remove,
+}
+
+// A storage backend: an ordered map from key to its stored record. Versioning rules
+// (last-writer-wins, retained history, purging) live in Store; engines only need to
+// provide an atomic read-modify-write.
+pub trait StorageEngine: Send + Sync + std::fmt::Debug {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Value>>;
+
+    // Replaces the key's record with whatever `f` decides from the current one, with no
+    // other update to the key in between; returns the record `f` saw last. `f` may be
+    // called more than once if the engine has to retry.
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<&Value>) -> Update) -> anyhow::Result<Option<Value>>;
+
+    // `update` for several distinct keys at once: readers see either none of the changes
+    // or all of them
+    fn update_all(&self, keys: &[String], f: &mut dyn FnMut(&str, Option<&Value>) -> Update) -> anyhow::Result<()>;
+
+    // every key, tombstones included
+    fn scan(&self) -> anyhow::Result<Vec<(String, Value)>>;
+
+    // up to `limit` keys within the bounds in key order, tombstones included
+    fn range(&self, start: Bound<&str>, end: Bound<&str>, limit: usize) -> anyhow::Result<Vec<(String, Value)>>;
+
+    // drops everything and loads `data` instead, used when loading a snapshot
+    fn replace_all(&self, data: HashMap<String, Value>) -> anyhow::Result<()>;
+
+    // Engines that persist their own data record checkpoints next to it. The rest keep the
+    // defaults and get snapshot files written for them instead.
+    fn persists_itself(&self) -> bool {
+        false
+    }
+
+    fn save_checkpoint(&self, _checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        Ok(None)
     }
 }
 
+// how many entries a scan pulls from the engine at a time while skipping tombstones
+const SCAN_CHUNK: usize = 256;
+
+// result of a read at a timestamp
+#[derive(Debug)]
+pub enum AsOf {
+    Found(Value),
+    Missing,   // the key had no version at that time
+    Truncated, // every retained version is newer; older ones were dropped
+}
+
+// A point in the past to read at: a Lamport timestamp and, with the hybrid clock, the wall
+// time it was taken at. Without a wall time nothing says when a deadline passed in Lamport
+// time, so a value counts as expired only from the tombstone the reaper wrote for it.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadAt {
+    pub ts: u64,
+    pub wall_ms: Option<u64>,
+}
+
+impl ReadAt {
+    // whether `version`, the key's latest at `ts`, was live then
+    pub fn sees_live(&self, version: &Value) -> bool {
+        version.data.is_some() && self.wall_ms.is_none_or(|ms| !version.is_expired(ms))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScanPage {
+    pub items: Vec<(String, Value)>,
+    pub next: Option<String>, // last key returned, if there may be more after it
+}
+
 #[derive(Debug)]
 pub struct Store {
-    inner: RwLock<HashMap<String, Value>>
+    engine: Box<dyn StorageEngine>,
+    versions: usize, // versions kept per key, the latest included
+    siblings: bool,  // keep concurrent writes instead of picking one by LWW
+    // this node's view of every counter it incremented, so concurrent increments each raise
+    // its total instead of racing to the same one
+    counting: Mutex<HashMap<String, PnCounter>>,
+    indexes: Vec<SecondaryIndex>,
+    // (expires_at, key) for every value written with a TTL; entries can be stale
+    // and are checked against the engine when they come due
+    expiring: Mutex<BTreeSet<(u64, String)>>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Store::new()
+    }
 }
 
 impl Store {
     pub fn new() -> Self {
+        Store::with_engine(Box::new(MemoryEngine::new()))
+    }
+
+    pub fn with_engine(engine: Box<dyn StorageEngine>) -> Self {
         Store {
-            inner: RwLock::new(HashMap::new())
+            engine,
+            versions: 1,
+            siblings: false,
+            counting: Mutex::new(HashMap::new()),
+            indexes: Vec::new(),
+            expiring: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    // keep up to `versions` versions of every key (at least the latest) for reads at a timestamp
+    pub fn retain_versions(mut self, versions: usize) -> Self {
+        self.versions = versions.max(1);
+        self
+    }
+
+    // track causality with each write's context and keep concurrent writes as siblings
+    pub fn keep_siblings(mut self) -> Self {
+        self.siblings = true;
+        self
+    }
+
+    pub fn keeps_siblings(&self) -> bool {
+        self.siblings
+    }
+
+    // maintain a secondary index for each definition; they start empty, see rebuild_indexes
+    pub fn with_indexes(mut self, defs: Vec<IndexDef>) -> Self {
+        self.indexes = defs.into_iter().map(SecondaryIndex::new).collect();
+        self
+    }
+
+    pub fn indexes(&self) -> impl Iterator<Item = &IndexDef> {
+        self.indexes.iter().map(SecondaryIndex::def)
+    }
+
+    // fills the indexes from whatever the engine holds, e.g. after recovery
+    pub fn rebuild_indexes(&self) -> anyhow::Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+        let records = self.engine.scan()?;
+        for index in &self.indexes {
+            index.rebuild(&records);
+        }
+        Ok(())
+    }
+
+    fn reindex(&self, key: &str, before: Option<&Value>, after: Option<&Value>) {
+        for index in &self.indexes {
+            index.update(key, before, after);
+        }
+    }
+
+    // Up to `limit` live keys whose `field` holds `value` in index `name`, in key order,
+    // resuming after `after`; None if there is no such index. Hits are checked against the
+    // store, so expired values don't match.
+    pub fn index_lookup(&self, name: &str, value: &str, after: Option<&str>, limit: usize) -> anyhow::Result<Option<ScanPage>> {
+        let Some(index) = self.indexes.iter().find(|i| i.def().name == name) else {
+            return Ok(None);
+        };
+        let now = now_ms();
+        let mut after = after.map(str::to_string);
+        let mut items = Vec::new();
+        loop {
+            let keys = index.lookup(value, after.as_deref(), SCAN_CHUNK);
+            let exhausted = keys.len() < SCAN_CHUNK;
+            after = keys.last().cloned();
+            for key in keys {
+                let Some(record) = self.get(&key)? else { continue };
+                if index.values(&key, Some(&record), Some(now)).contains(value) {
+                    items.push((key, record));
+                    if items.len() > limit {
+                        break;
+                    }
+                }
+            }
+            if exhausted || items.len() > limit {
+                break;
+            }
+        }
+
+        let mut next = None;
+        if items.len() > limit {
+            items.truncate(limit);
+            next = items.last().map(|(key, _)| key.clone());
+        }
+        Ok(Some(ScanPage { items, next }))
+    }
+
+    // Folds `incoming` into the key's siblings, which are the versions no other version has
+    // superseded. A write supersedes what its context covers, so the result only depends on
+    // which writes were applied and not on their order. Returns None if nothing changes: a
+    // duplicate, or a version a sibling has already superseded.
+    fn merge_siblings(current: Option<&Value>, incoming: &Value) -> Option<Value> {
+        let Some(current) = current else {
+            return Some(incoming.clone());
+        };
+        let siblings = current.clone().into_siblings();
+        if siblings.iter().any(|s| s.version() == incoming.version() || s.supersedes(incoming)) {
+            return None;
+        }
+        let mut kept: Vec<Value> = siblings.into_iter().filter(|s| !incoming.supersedes(s)).collect();
+        kept.push(incoming.clone());
+        Some(Value::from_siblings(kept))
+    }
+
+    // Joins a counter or set write into the key. Once a key holds one, plain puts and deletes
+    // no longer apply to it, deleted or not, until GC purges it, and it replaces a plain value whatever their timestamps: that
+    // keeps the outcome independent of the order writes arrive in. The record is stamped
+    // with the newest write joined into it. Returns None if nothing changes.
+    fn merge_crdt(current: Option<&Value>, incoming: &Value) -> Option<Value> {
+        let crdt = incoming.crdt.as_ref()?;
+        let Some(current) = current.filter(|c| c.crdt.is_some()) else {
+            return Some(incoming.clone());
+        };
+        let merged = current.crdt.as_ref().unwrap().merged(crdt)?;
+        let newest = if incoming.is_newer_than(Some(current)) { incoming } else { current };
+        Some(Value::of_crdt(merged, newest.ts, newest.node_id))
+    }
+
+    // Folds `incoming` into the key's record. The newest version is the record itself and up
+    // to `versions - 1` older ones sit in its history. Versions are ordered by (ts, node_id)
+    // rather than arrival, so replicas that applied the same writes keep the same history.
+    // Returns None if nothing changes: a duplicate, or older than every version kept.
+    fn merge(&self, current: Option<&Value>, incoming: &Value) -> Option<Value> {
+        if incoming.crdt.is_some() || current.is_some_and(|c| c.crdt.is_some()) {
+            return Store::merge_crdt(current, incoming);
+        }
+        if self.siblings {
+            return Store::merge_siblings(current, incoming);
+        }
+        let Some(current) = current else {
+            return Some(incoming.clone());
+        };
+        let mut versions = current.clone().into_versions();
+        if versions.iter().any(|v| v.version() == incoming.version()) {
+            return None;
+        }
+        let at = versions.iter().position(|v| incoming.is_newer_than(Some(v))).unwrap_or(versions.len());
+        if at >= self.versions {
+            return None;
+        }
+        versions.insert(at, incoming.clone());
+        versions.truncate(self.versions);
+
+        let mut versions = versions.into_iter();
+        let mut latest = versions.next().unwrap();
+        latest.history = versions.collect();
+        Some(latest)
+    }
+
+    // Returns the value `incoming` replaced, or None if it lost. With siblings, and for
+    // counters and sets, a write that lands "replaces" the record it was merged into, even if
+    // other siblings remain.
+    fn write(&self, key: &str, incoming: Value) -> anyhow::Result<Option<Value>> {
+        let deadline = incoming.expires_at.filter(|_| incoming.data.is_some());
+        let mut won = false;
+        let mut stored = None;
+        let previous = self.engine.update(key, &mut |current| {
+            let merged = self.merge(current, &incoming);
+            won = merged.is_some()
+                && (self.siblings || incoming.crdt.is_some() || incoming.is_newer_than(current));
+            stored = if self.indexes.is_empty() { None } else { merged.clone() };
+            match merged {
+                Some(record) => Update::Set(record),
+                None => Update::Keep,
+            }
+        })?;
+        if stored.is_some() {
+            self.reindex(key, previous.as_ref(), stored.as_ref());
+        }
+        if let Some(at) = deadline {
+            self.expiring.lock().unwrap().insert((at, key.to_string()));
+        }
+        Ok(previous.filter(|_| won).map(Value::without_history))
+    }
+
+    // removes the key only if its latest version is the tombstone (ts, node_id) and it has no
+    // siblings; returns whether it did
+    fn purge(&self, key: &str, ts: u64, node_id: u64) -> anyhow::Result<bool> {
+        let mut purged = false;
+        self.engine.update(key, &mut |current| {
+            purged = current.is_some_and(|v| {
+                v.data.is_none() && v.siblings.is_empty() && v.ts == ts && v.node_id == node_id
+            });
+            if purged { Update::Remove } else { Update::Keep }
+        })?;
+        if purged {
+            // a counter made on the key from now on counts from zero
+            self.counting.lock().unwrap().remove(key);
         }
+        Ok(purged)
+    }
+
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<Value>> {
+        Ok(self.engine.get(key)?.map(Value::without_history))
+    }
+
+    // The version of `key` that was latest at Lamport time `ts`: the newest one stamped at or
+    // before it. Tombstones are returned like any other version.
+    pub fn get_as_of(&self, key: &str, ts: u64) -> anyhow::Result<AsOf> {
+        let Some(record) = self.engine.get(key)? else {
+            return Ok(AsOf::Missing);
+        };
+        let versions = record.into_versions();
+        let full = versions.len() >= self.versions;
+        Ok(match versions.into_iter().find(|v| v.ts <= ts) {
+            Some(v) => AsOf::Found(v),
+            // older versions may have been dropped, so there is no telling what was there
+            None if full => AsOf::Truncated,
+            None => AsOf::Missing,
+        })
+    }
+
+    // Whether applying `op` stamped (ts, node_id) would change nothing: the key already holds
+    // that exact version, it is older than every version the key keeps (with siblings: a
+    // sibling has superseded it), or a counter or set already includes it.
+    pub fn is_stale(&self, op: &Operation, ts: u64, node_id: u64) -> anyhow::Result<bool> {
+        let (key, incoming) = Store::version_of(op.clone(), ts, node_id)?;
+        Ok(self.merge(self.engine.get(&key)?.as_ref(), &incoming).is_none())
     }
 
-    // only put if the incoming value is newer (based on Lamport timestamp and node_id)
-    pub async fn put(&self, key: String, value: String, ts: u64, node_id: u64) {
-        let incoming = Value { data: Some(value), ts, node_id };
-        let mut map = self.inner.write().await;
+    // every retained version of `key`, newest first, tombstones included
+    pub fn history(&self, key: &str) -> anyhow::Result<Vec<Value>> {
+        Ok(self.engine.get(key)?.map(Value::into_versions).unwrap_or_default())
+    }
 
-        let current = map.get(&key);
-        if incoming.is_newer_than(current) {
-            map.insert(key, incoming);
+    // The version a write makes. Deletes write None to represent a tombstone.
+    fn version_of(op: Operation, ts: u64, node_id: u64) -> anyhow::Result<(String, Value)> {
+        match op {
+            Operation::Put { key, value, content_type, expires_at, context } => {
+                let value = Value {
+                    data: Some(value),
+                    content_type,
+                    expires_at,
+                    context: context.unwrap_or_default(),
+                    ..Value::tombstone(ts, node_id)
+                };
+                Ok((key, value))
+            }
+            Operation::Delete { key, context } => {
+                let tombstone = Value {
+                    deleted_at: Some(now_ms()),
+                    context: context.unwrap_or_default(),
+                    ..Value::tombstone(ts, node_id)
+                };
+                Ok((key, tombstone))
+            }
+            Operation::Counter { key, delta } => Ok((key, Value::of_crdt(Crdt::Counter(delta), ts, node_id))),
+            Operation::Set { key, delta } => Ok((key, Value::of_crdt(Crdt::Set(delta), ts, node_id))),
+            other => anyhow::bail!("not a write to one key: {:?}", other),
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Value> {
-        let map = self.inner.read().await;
-        map.get(key).cloned()
+    // applies a logged operation; returns the value it replaced, if the entry won
+    // (batches touch several keys and return None)
+    pub fn apply(&self, entry: LogEntry) -> anyhow::Result<Option<Value>> {
+        match entry.operation {
+            op @ (Operation::Put { .. } | Operation::Delete { .. } | Operation::Counter { .. } | Operation::Set { .. }) => {
+                let (key, value) = Store::version_of(op, entry.ts, entry.node_id)?;
+                self.write(&key, value)
+            }
+            Operation::Batch { ops } => {
+                self.apply_batch(ops, entry.ts, entry.node_id)?;
+                Ok(None)
+            }
+            Operation::Purge { key } => {
+                let purged = self.purge(&key, entry.ts, entry.node_id)?;
+                Ok(purged.then(|| Value::tombstone(entry.ts, entry.node_id)))
+            }
+        }
     }
 
-    // delete writes None into the map to represent a tombstone
-    pub async fn delete(&self, key: &str, ts: u64, node_id: u64) -> Option<Value> {
-        let incoming = Value { data: None, ts, node_id };
-        let mut map = self.inner.write().await;
+    // The counter state adding `by` to `key` on this node. Totals are handed out under a lock
+    // and only joined in later, so an increment whose write fails may still be counted by
+    // the next one, as if it had succeeded.
+    pub fn counter_delta(&self, key: &str, node_id: u64, by: i64) -> anyhow::Result<PnCounter> {
+        let stored = match self.engine.get(key)?.and_then(|v| v.crdt).map(|crdt| *crdt) {
+            Some(Crdt::Counter(counter)) => counter,
+            _ => PnCounter::default(),
+        };
+        let mut counting = self.counting.lock().unwrap();
+        let ours = counting.entry(key.to_string()).or_default();
+        ours.merge(&stored);
+        let delta = ours.bumped(node_id, by);
+        ours.merge(&delta);
+        Ok(delta)
+    }
 
-        match map.get(key) {
+    // Up to `limit` tombstones stored at or before `cutoff_ms`, in key order. Tombstones from
+    // before deletion times were recorded count as old enough.
+    pub fn old_tombstones(&self, cutoff_ms: u64, limit: usize) -> anyhow::Result<Vec<(String, Value)>> {
+        let mut found = Vec::new();
+        let mut lower = Bound::Unbounded;
+        loop {
+            let chunk = self.engine.range(lower.as_ref().map(String::as_str), Bound::Unbounded, SCAN_CHUNK)?;
+            let exhausted = chunk.len() < SCAN_CHUNK;
+            if let Some((last, _)) = chunk.last() {
+                lower = Bound::Excluded(last.clone());
+            }
+            for (key, value) in chunk {
+                if value.data.is_none() && value.siblings.is_empty() && value.deleted_at.is_none_or(|at| at <= cutoff_ms) {
+                    found.push((key, value));
+                    if found.len() >= limit {
+                        return Ok(found);
+                    }
+                }
+            }
+            if exhausted {
+                return Ok(found);
+            }
+        }
+    }
+
+    // Every op of a batch shares its (ts, node_id) and goes to the engine in one atomic write.
+    // Batches only carry puts and deletes on distinct keys (the API checks this).
+    fn apply_batch(&self, ops: Vec<Operation>, ts: u64, node_id: u64) -> anyhow::Result<()> {
+        let writes = ops
+            .into_iter()
+            .map(|op| Store::version_of(op, ts, node_id))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let deadlines: Vec<(u64, String)> = writes
+            .iter()
+            .filter_map(|(key, v)| v.expires_at.filter(|_| v.data.is_some()).map(|at| (at, key.clone())))
+            .collect();
+        let keys: Vec<String> = writes.iter().map(|(key, _)| key.clone()).collect();
+        let incoming: HashMap<String, Value> = writes.into_iter().collect();
+        // the engine may retry, so only the last attempt per key counts
+        let mut changed: HashMap<String, (Option<Value>, Value)> = HashMap::new();
+        self.engine.update_all(&keys, &mut |key, current| match self.merge(current, &incoming[key]) {
+            Some(record) => {
+                if !self.indexes.is_empty() {
+                    changed.insert(key.to_string(), (current.cloned(), record.clone()));
+                }
+                Update::Set(record)
+            }
             None => {
-                map.insert(key.to_string(), incoming);
-                None
-            },
-            Some(existing) => {
-                if incoming.is_newer_than(Some(existing)) {
-                    let prev = map.insert(key.to_string(), incoming);
-                    prev
-                } else {
-                    None
+                changed.remove(key);
+                Update::Keep
+            }
+        })?;
+        for (key, (before, after)) in &changed {
+            self.reindex(key, before.as_ref(), Some(after));
+        }
+        self.expiring.lock().unwrap().extend(deadlines);
+        Ok(())
+    }
+
+    // Takes the keys whose deadline has passed by `now_ms` and returns the ones that still
+    // hold the expired value, i.e. that were not overwritten or deleted in the meantime;
+    // each expired sibling comes back on its own.
+    pub fn take_expired(&self, now_ms: u64) -> anyhow::Result<Vec<(String, Value)>> {
+        let due: Vec<String> = {
+            let mut expiring = self.expiring.lock().unwrap();
+            let later = expiring.split_off(&(now_ms + 1, String::new()));
+            std::mem::replace(&mut *expiring, later).into_iter().map(|(_, key)| key).collect()
+        };
+
+        let mut expired = Vec::new();
+        for key in due {
+            let siblings = self.get(&key)?.map(Value::into_siblings).unwrap_or_default();
+            for value in siblings {
+                if value.data.is_some() && value.is_expired(now_ms) {
+                    expired.push((key.clone(), value));
+                }
+            }
+        }
+        Ok(expired)
+    }
+
+    // puts a key back on the expiry list, e.g. after its tombstone failed to commit
+    pub fn track_expiry(&self, key: &str, expires_at: u64) {
+        self.expiring.lock().unwrap().insert((expires_at, key.to_string()));
+    }
+
+    // rebuilds the expiry list from whatever the engine holds, e.g. after recovery
+    pub fn reindex_expiries(&self) -> anyhow::Result<()> {
+        let mut expiring = BTreeSet::new();
+        for (key, record) in self.engine.scan()? {
+            for value in record.into_siblings() {
+                if let Some(at) = value.expires_at.filter(|_| value.data.is_some()) {
+                    expiring.insert((at, key.clone()));
+                }
+            }
+        }
+        *self.expiring.lock().unwrap() = expiring;
+        Ok(())
+    }
+
+    // Live keys with the given prefix in [start, end), in key order, resuming after `after`
+    // when continuing a previous page. Tombstones and expired values are skipped and don't
+    // count toward `limit`. With `as_of`, each key is read as its version at that time (see
+    // get_as_of) and checked for expiry then; keys whose version there was not retained are
+    // skipped too. Siblings aren't retained as history, so callers don't combine the two.
+    pub fn scan_live(
+        &self,
+        prefix: &str,
+        start: Option<&str>,
+        end: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+        as_of: Option<ReadAt>,
+    ) -> anyhow::Result<ScanPage> {
+        let begin = start.filter(|s| *s > prefix).unwrap_or(prefix);
+        let mut lower = match after {
+            Some(a) if a >= begin => Bound::Excluded(a.to_string()),
+            _ => Bound::Included(begin.to_string()),
+        };
+        let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+
+        // read one past the limit to know whether another page exists
+        let now = now_ms();
+        let mut items = Vec::new();
+        'scan: loop {
+            let chunk = self.engine.range(lower.as_ref().map(String::as_str), upper, SCAN_CHUNK)?;
+            let exhausted = chunk.len() < SCAN_CHUNK;
+            if let Some((last, _)) = chunk.last() {
+                lower = Bound::Excluded(last.clone());
+            }
+            for (key, value) in chunk {
+                // keys are ordered, so the first one outside the prefix ends the scan
+                if !key.starts_with(prefix) {
+                    break 'scan;
+                }
+                let value = match as_of {
+                    Some(at) => value.into_versions().into_iter().find(|v| v.ts <= at.ts).filter(|v| at.sees_live(v)),
+                    None => Some(value.without_history()).filter(|v| v.is_live(now)),
+                };
+                if let Some(value) = value {
+                    items.push((key, value));
+                    if items.len() > limit {
+                        break 'scan;
+                    }
                 }
             }
+            if exhausted {
+                break;
+            }
         }
+
+        let mut next = None;
+        if items.len() > limit {
+            items.truncate(limit);
+            next = items.last().map(|(key, _)| key.clone());
+        }
+        Ok(ScanPage { items, next })
+    }
+
+    // copy of the whole keyspace (tombstones and history included) for snapshotting
+    pub fn dump(&self) -> anyhow::Result<HashMap<String, Value>> {
+        Ok(self.engine.scan()?.into_iter().collect())
+    }
+
+    // replace the whole keyspace, used when loading a snapshot
+    pub fn restore(&self, data: HashMap<String, Value>) -> anyhow::Result<()> {
+        self.engine.replace_all(data)?;
+        self.rebuild_indexes()
     }
-}
\ No newline at end of file
+
+    pub fn persists_itself(&self) -> bool {
+        self.engine.persists_itself()
+    }
+
+    pub fn save_checkpoint(&self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        self.engine.save_checkpoint(checkpoint)
+    }
+
+    pub fn load_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        self.engine.load_checkpoint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(ts: u64, node_id: u64, key: &str, value: &str) -> LogEntry {
+        let operation = Operation::Put { key: key.to_string(), value: value.as_bytes().to_vec(), content_type: None, expires_at: None, context: None };
+        LogEntry { ts, node_id, operation }
+    }
+
+    fn put_expiring(ts: u64, key: &str, expires_at: u64) -> LogEntry {
+        let operation = Operation::Put { key: key.to_string(), value: b"v".to_vec(), content_type: None, expires_at: Some(expires_at), context: None };
+        LogEntry { ts, node_id: 1, operation }
+    }
+
+    fn delete(ts: u64, node_id: u64, key: &str) -> LogEntry {
+        LogEntry { ts, node_id, operation: Operation::Delete { key: key.to_string(), context: None } }
+    }
+
+    // the entry, written by a client that had read `seen`
+    fn after(mut entry: LogEntry, seen: &[(u64, u64)]) -> LogEntry {
+        let context = Some(seen.iter().copied().collect());
+        match &mut entry.operation {
+            Operation::Put { context: c, .. } | Operation::Delete { context: c, .. } => *c = context,
+            _ => unreachable!(),
+        }
+        entry
+    }
+
+    fn data(value: &Value) -> Vec<Option<&str>> {
+        let siblings = std::iter::once(value).chain(&value.siblings);
+        siblings.map(|v| v.data.as_deref().map(|d| std::str::from_utf8(d).unwrap())).collect()
+    }
+
+    fn keys(page: &ScanPage) -> Vec<&str> {
+        page.items.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    #[test]
+    fn as_of_reads_check_expiry_at_that_time() {
+        let store = Store::new().retain_versions(3);
+        store.apply(put_expiring(10, "a", 1_000)).unwrap();
+
+        let AsOf::Found(version) = store.get_as_of("a", 15).unwrap() else {
+            panic!("a had a version at 15");
+        };
+        assert!(ReadAt { ts: 15, wall_ms: Some(500) }.sees_live(&version));
+        assert!(!ReadAt { ts: 15, wall_ms: Some(2_000) }.sees_live(&version));
+        // no wall time: live until the reaper's tombstone
+        assert!(ReadAt { ts: 15, wall_ms: None }.sees_live(&version));
+
+        let then = Some(ReadAt { ts: 15, wall_ms: Some(500) });
+        assert_eq!(keys(&store.scan_live("", None, None, None, 10, then).unwrap()), vec!["a"]);
+        assert!(store.scan_live("", None, None, None, 10, None).unwrap().items.is_empty());
+
+        store.apply(delete(20, 1, "a")).unwrap();
+        let before = Some(ReadAt { ts: 15, wall_ms: None });
+        let after = Some(ReadAt { ts: 25, wall_ms: None });
+        assert_eq!(keys(&store.scan_live("", None, None, None, 10, before).unwrap()), vec!["a"]);
+        assert!(store.scan_live("", None, None, None, 10, after).unwrap().items.is_empty());
+    }
+
+    #[test]
+    fn as_of_reads_say_when_history_no_longer_reaches_back() {
+        let store = Store::new().retain_versions(2);
+        for ts in [10, 20, 30] {
+            store.apply(put(ts, 1, "a", &ts.to_string())).unwrap();
+        }
+        assert!(matches!(store.get_as_of("a", 25).unwrap(), AsOf::Found(v) if v.ts == 20));
+        assert!(matches!(store.get_as_of("a", 15).unwrap(), AsOf::Truncated));
+        assert!(matches!(store.get_as_of("b", 15).unwrap(), AsOf::Missing));
+    }
+
+    #[test]
+    fn concurrent_puts_are_kept_as_siblings_in_any_order() {
+        let writes = [put(10, 1, "a", "x"), put(10, 2, "a", "y"), put(11, 1, "a", "z")];
+        let forward = Store::new().keep_siblings();
+        let backward = Store::new().keep_siblings();
+        for w in &writes {
+            forward.apply(w.clone()).unwrap();
+        }
+        for w in writes.iter().rev() {
+            backward.apply(w.clone()).unwrap();
+        }
+
+        let a = forward.get("a").unwrap().unwrap();
+        assert_eq!(data(&a), vec![Some("z"), Some("y"), Some("x")]);
+        assert_eq!(data(&backward.get("a").unwrap().unwrap()), data(&a));
+        assert_eq!(a.causal_context(), VersionVector::from([(1, 11), (2, 10)]));
+        // a copy of a sibling changes nothing
+        assert!(forward.is_stale(&writes[1].operation, 10, 2).unwrap());
+    }
+
+    #[test]
+    fn a_write_carrying_the_context_replaces_the_siblings_it_saw() {
+        let store = Store::new().keep_siblings();
+        store.apply(put(10, 1, "a", "x")).unwrap();
+        store.apply(put(10, 2, "a", "y")).unwrap();
+        let context: Vec<_> = store.get("a").unwrap().unwrap().causal_context().into_iter().collect();
+
+        store.apply(after(put(12, 3, "a", "merged"), &context)).unwrap();
+        assert_eq!(data(&store.get("a").unwrap().unwrap()), vec![Some("merged")]);
+
+        // a sibling that arrives late is already superseded
+        let late = put(10, 2, "a", "y");
+        assert!(store.is_stale(&late.operation, 10, 2).unwrap());
+        store.apply(late).unwrap();
+        assert_eq!(data(&store.get("a").unwrap().unwrap()), vec![Some("merged")]);
+    }
+
+    #[test]
+    fn a_delete_racing_a_put_leaves_the_put_standing() {
+        let store = Store::new().keep_siblings();
+        store.apply(put(10, 1, "a", "x")).unwrap();
+        // the delete saw only x; y was written concurrently
+        store.apply(after(delete(12, 2, "a"), &[(1, 10)])).unwrap();
+        store.apply(put(11, 3, "a", "y")).unwrap();
+
+        let a = store.get("a").unwrap().unwrap();
+        assert_eq!(data(&a), vec![Some("y"), None]);
+        assert!(a.deleted_at.is_none());
+
+        // deleting with the full context removes the record
+        let context: Vec<_> = a.causal_context().into_iter().collect();
+        store.apply(after(delete(13, 1, "a"), &context)).unwrap();
+        let a = store.get("a").unwrap().unwrap();
+        assert_eq!(data(&a), vec![None]);
+        assert!(a.deleted_at.is_some());
+    }
+
+    #[test]
+    fn scans_page_through_live_keys_in_order() {
+        let store = Store::new();
+        for (ts, key) in ["user/d", "user/a", "user/c", "user/b", "users", "admin"].into_iter().enumerate() {
+            store.apply(put(ts as u64 + 1, 1, key, "v")).unwrap();
+        }
+        store.apply(delete(10, 1, "user/b")).unwrap();
+
+        let first = store.scan_live("user/", None, None, None, 2, None).unwrap();
+        assert_eq!(keys(&first), vec!["user/a", "user/c"]);
+        assert_eq!(first.next.as_deref(), Some("user/c"));
+        let last = store.scan_live("user/", None, None, first.next.as_deref(), 2, None).unwrap();
+        assert_eq!(keys(&last), vec!["user/d"]);
+        assert_eq!(last.next, None);
+
+        // [start, end) within the prefix
+        let range = store.scan_live("user/", Some("user/b"), Some("user/d"), None, 10, None).unwrap();
+        assert_eq!(keys(&range), vec!["user/c"]);
+        let all = store.scan_live("", None, None, None, 10, None).unwrap();
+        assert_eq!(keys(&all), vec!["admin", "user/a", "user/c", "user/d", "users"]);
+    }
+
+    #[test]
+    fn a_batch_applies_every_write_under_one_stamp() {
+        let dir = std::env::temp_dir().join(format!("kv-engine-batch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let stores = [Store::new(), Store::with_engine(Box::new(crate::store::SledEngine::open(&dir).unwrap()))];
+        for store in &stores {
+            store.apply(put(10, 1, "b", "old")).unwrap();
+            store.apply(put(30, 1, "c", "newer")).unwrap();
+            let ops = [put(20, 2, "a", "1"), delete(20, 2, "b"), put(20, 2, "c", "3")].map(|e| e.operation);
+            store.apply(LogEntry { ts: 20, node_id: 2, operation: Operation::Batch { ops: ops.to_vec() } }).unwrap();
+
+            let a = store.get("a").unwrap().unwrap();
+            assert_eq!((a.data.as_deref(), a.version()), (Some(&b"1"[..]), (20, 2)));
+            assert!(store.get("b").unwrap().unwrap().data.is_none());
+            // each key still keeps its newest write
+            assert_eq!(store.get("c").unwrap().unwrap().data.as_deref(), Some(&b"newer"[..]));
+        }
+        drop(stores);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use std::{collections::BTreeSet, ops::Bound, str::FromStr, sync::RwLock};
+
+use crate::store::Value;
+
+// A secondary index over one field of the JSON documents stored under a key prefix, declared
+// as `name=prefix:field` with a dotted field path, e.g. `by_email=users/:contact.email`.
+#[derive(Debug, Clone)]
+pub struct IndexDef {
+    pub name: String,
+    pub prefix: String,
+    pub field: String,
+}
+
+impl FromStr for IndexDef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let malformed = || format!("index {:?} should look like name=prefix:field", s);
+        let (name, rest) = s.split_once('=').ok_or_else(malformed)?;
+        // prefixes may contain ':' themselves, field paths don't
+        let (prefix, field) = rest.rsplit_once(':').ok_or_else(malformed)?;
+        if name.is_empty() || field.is_empty() {
+            return Err(malformed());
+        }
+        Ok(IndexDef { name: name.to_string(), prefix: prefix.to_string(), field: field.to_string() })
+    }
+}
+
+// The field's values for every key under the prefix, kept in memory as (value, key) pairs and
+// rebuilt from the engine at startup. Values that aren't JSON, or lack the field, aren't
+// indexed.
+#[derive(Debug)]
+pub struct SecondaryIndex {
+    def: IndexDef,
+    entries: RwLock<BTreeSet<(String, String)>>,
+}
+
+impl SecondaryIndex {
+    pub fn new(def: IndexDef) -> Self {
+        SecondaryIndex { def, entries: RwLock::new(BTreeSet::new()) }
+    }
+
+    pub fn def(&self) -> &IndexDef {
+        &self.def
+    }
+
+    // The field's values in the record: the latest version's and, with siblings, theirs too.
+    // With `now`, expired values are left out.
+    pub fn values(&self, key: &str, record: Option<&Value>, now: Option<u64>) -> BTreeSet<String> {
+        if !key.starts_with(&self.def.prefix) {
+            return BTreeSet::new();
+        }
+        record
+            .into_iter()
+            .flat_map(|r| std::iter::once(r).chain(&r.siblings))
+            .filter(|v| now.is_none_or(|now| !v.is_expired(now)))
+            .filter_map(|v| v.data.as_deref())
+            .flat_map(|data| field_values(data, &self.def.field))
+            .collect()
+    }
+
+    // moves the key's entries from what `before` held to what `after` holds
+    pub fn update(&self, key: &str, before: Option<&Value>, after: Option<&Value>) {
+        let old = self.values(key, before, None);
+        let new = self.values(key, after, None);
+        if old == new {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap();
+        for value in old.difference(&new) {
+            entries.remove(&(value.clone(), key.to_string()));
+        }
+        for value in new.difference(&old) {
+            entries.insert((value.clone(), key.to_string()));
+        }
+    }
+
+    pub fn rebuild(&self, records: &[(String, Value)]) {
+        let entries = records
+            .iter()
+            .flat_map(|(key, record)| {
+                self.values(key, Some(record), None).into_iter().map(move |value| (value, key.clone()))
+            })
+            .collect();
+        *self.entries.write().unwrap() = entries;
+    }
+
+    // up to `limit` keys indexed under `value`, in key order, after `after`
+    pub fn lookup(&self, value: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        let lower = match after {
+            Some(key) => Bound::Excluded((value.to_string(), key.to_string())),
+            None => Bound::Included((value.to_string(), String::new())),
+        };
+        self.entries
+            .read()
+            .unwrap()
+            .range((lower, Bound::Unbounded))
+            .take_while(|(v, _)| v == value)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+// The field at a dotted path of a JSON document, as text: strings as they are, numbers and
+// booleans in their JSON form, and an array as each of its scalar elements.
+fn field_values(data: &[u8], path: &str) -> Vec<String> {
+    let Ok(doc) = serde_json::from_slice::<serde_json::Value>(data) else {
+        return Vec::new();
+    };
+    let Some(field) = path.split('.').try_fold(&doc, |v, name| v.get(name)) else {
+        return Vec::new();
+    };
+    let scalar = |v: &serde_json::Value| match v {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Some(v.to_string()),
+        _ => None,
+    };
+    match field {
+        serde_json::Value::Array(items) => items.iter().filter_map(scalar).collect(),
+        other => scalar(other).into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Store;
+    use crate::util::{LogEntry, Operation};
+
+    fn put(key: &str, value: &str) -> Operation {
+        Operation::Put { key: key.to_string(), value: value.as_bytes().to_vec(), content_type: None, expires_at: None, context: None }
+    }
+
+    fn lookup(store: &Store, value: &str) -> Vec<String> {
+        let page = store.index_lookup("by_email", value, None, 10).unwrap().unwrap();
+        page.items.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn parses_prefixes_that_contain_colons() {
+        let def: IndexDef = "by_tag=ns:posts/:meta.tags".parse().unwrap();
+        assert_eq!((def.name.as_str(), def.prefix.as_str(), def.field.as_str()), ("by_tag", "ns:posts/", "meta.tags"));
+        assert!("by_tag=posts/".parse::<IndexDef>().is_err());
+        assert!("=posts/:tags".parse::<IndexDef>().is_err());
+    }
+
+    #[test]
+    fn writes_keep_the_index_in_step_with_the_documents() {
+        let store = Store::new().with_indexes(vec!["by_email=users/:contact.email".parse().unwrap()]);
+        let apply = |ts, operation| store.apply(LogEntry { ts, node_id: 1, operation }).unwrap();
+        apply(1, put("users/1", r#"{"contact":{"email":"a@x"}}"#));
+        apply(2, put("users/2", r#"{"contact":{"email":"a@x"}}"#));
+        // outside the prefix, or not JSON: not indexed
+        apply(3, put("admins/1", r#"{"contact":{"email":"a@x"}}"#));
+        apply(4, put("users/3", "a@x"));
+        assert_eq!(lookup(&store, "a@x"), vec!["users/1", "users/2"]);
+
+        // changing the field moves the key; a lost write doesn't
+        apply(5, put("users/1", r#"{"contact":{"email":"b@x"}}"#));
+        apply(4, put("users/1", r#"{"contact":{"email":"c@x"}}"#));
+        assert_eq!(lookup(&store, "a@x"), vec!["users/2"]);
+        assert_eq!(lookup(&store, "b@x"), vec!["users/1"]);
+        assert!(lookup(&store, "c@x").is_empty());
+
+        // deletes and batches too
+        apply(6, Operation::Delete { key: "users/2".to_string(), context: None });
+        let ops = vec![put("users/4", r#"{"contact":{"email":"b@x"}}"#), Operation::Delete { key: "users/1".to_string(), context: None }];
+        apply(7, Operation::Batch { ops });
+        assert!(lookup(&store, "a@x").is_empty());
+        assert_eq!(lookup(&store, "b@x"), vec!["users/4"]);
+        assert!(store.index_lookup("missing", "b@x", None, 10).unwrap().is_none());
+    }
+}
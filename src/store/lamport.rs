@@ -1,20 +1,55 @@
 use std::sync::atomic::{AtomicU64, Ordering};
+use crate::util::now_ms;
+
+// In hybrid mode a timestamp is (unix ms << LOGICAL_BITS) | counter, so it reads as wall time
+// while still moving forward like a Lamport clock when wall time stalls or a peer is ahead.
+const LOGICAL_BITS: u32 = 16;
+// 2020-01-01; smaller physical parts come from Lamport timestamps, not from a wall clock
+const MIN_WALL_MS: u64 = 1_577_836_800_000;
 
 #[derive(Debug)]
 pub struct LamportClock {
     counter: AtomicU64,
+    hybrid: bool,
+}
+
+impl Default for LamportClock {
+    fn default() -> Self {
+        LamportClock::new()
+    }
 }
 
 impl LamportClock {
     pub fn new() -> Self {
         LamportClock {
             counter: AtomicU64::new(0),
+            hybrid: false,
+        }
+    }
+
+    // A hybrid logical clock. Every tick still lands above everything the clock has seen,
+    // which is all LWW relies on, and also at or above the local wall clock.
+    pub fn hybrid() -> Self {
+        LamportClock {
+            counter: AtomicU64::new(0),
+            hybrid: true,
         }
     }
 
+    // lowest timestamp the next tick may take
+    fn floor(&self) -> u64 {
+        if self.hybrid { now_ms() << LOGICAL_BITS } else { 0 }
+    }
+
     // when sending a message, increment the clock and return the new timestamp
     pub fn tick_send(&self) -> u64 {
-        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+        loop {
+            let current = self.counter.load(Ordering::SeqCst);
+            let next = (current + 1).max(self.floor());
+            if self.counter.compare_exchange_weak(current, next, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return next;
+            }
+        }
     }
 
     // when receiving a message with timestamp ts, update the clock
@@ -22,7 +57,7 @@ impl LamportClock {
     pub fn tick_recv(&self, ts: u64) -> u64 {
         loop {
             let current = self.counter.load(Ordering::SeqCst);
-            let next = current.max(ts) + 1;
+            let next = (current.max(ts) + 1).max(self.floor());
             if self.counter.compare_exchange_weak(current, next, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
                 return next;
             }
@@ -47,4 +82,55 @@ impl LamportClock {
     pub fn tick_now(&self) -> u64 {
         self.counter.load(Ordering::SeqCst)
     }
-}
\ No newline at end of file
+
+    // Unix ms a hybrid timestamp was taken at. None for a logical clock, and for timestamps
+    // that carry no wall time (e.g. ones written before the cluster switched clocks).
+    pub fn wall_ms(&self, ts: u64) -> Option<u64> {
+        let ms = ts >> LOGICAL_BITS;
+        (self.hybrid && ms >= MIN_WALL_MS).then_some(ms)
+    }
+
+    // the lowest hybrid timestamp taken at or after `wall_ms`; None for a logical clock
+    pub fn ts_at(&self, wall_ms: u64) -> Option<u64> {
+        self.hybrid.then_some(wall_ms << LOGICAL_BITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hybrid_ticks_read_as_wall_time_and_still_move_forward() {
+        let clock = LamportClock::hybrid();
+        let before = now_ms();
+        let first = clock.tick_send();
+        let wall = clock.wall_ms(first).unwrap();
+        assert!(wall >= before && wall <= now_ms());
+        assert!(clock.tick_send() > first);
+
+        // a peer ahead of the wall clock pulls it forward, with the same wall time
+        let ahead = (now_ms() + 60_000) << LOGICAL_BITS;
+        let next = clock.tick_recv(ahead);
+        assert_eq!(next, ahead + 1);
+        assert_eq!(clock.wall_ms(next), clock.wall_ms(ahead));
+        assert!(clock.tick_send() > next);
+    }
+
+    #[test]
+    fn hybrid_timestamps_order_after_lamport_ones() {
+        let clock = LamportClock::hybrid();
+        clock.tick_observe(42);
+        assert!(clock.tick_send() > 42);
+        assert_eq!(clock.wall_ms(42), None);
+        assert_eq!(LamportClock::new().wall_ms(clock.tick_now()), None);
+        assert_eq!(LamportClock::new().ts_at(now_ms()), None);
+    }
+
+    #[test]
+    fn ts_at_bounds_every_tick_taken_from_then_on() {
+        let clock = LamportClock::hybrid();
+        let cutoff = clock.ts_at(now_ms()).unwrap();
+        assert!(clock.tick_send() >= cutoff);
+    }
+}
@@ -0,0 +1,92 @@
+use std::{collections::{BTreeMap, HashMap}, ops::Bound, sync::RwLock};
+use crate::store::{StorageEngine, Update, Value};
+
+// keeps everything in RAM, ordered by key; durability comes from snapshots plus the WAL
+#[derive(Debug)]
+pub struct MemoryEngine {
+    inner: RwLock<BTreeMap<String, Value>>,
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        MemoryEngine::new()
+    }
+}
+
+impl MemoryEngine {
+    pub fn new() -> Self {
+        MemoryEngine {
+            inner: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Value>> {
+        let map = self.inner.read().unwrap();
+        Ok(map.get(key).cloned())
+    }
+
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<&Value>) -> Update) -> anyhow::Result<Option<Value>> {
+        let mut map = self.inner.write().unwrap();
+        let current = map.get(key).cloned();
+        match f(current.as_ref()) {
+            Update::Keep => {}
+            Update::Set(record) => {
+                map.insert(key.to_string(), record);
+            }
+            Update::Remove => {
+                map.remove(key);
+            }
+        }
+        Ok(current)
+    }
+
+    fn update_all(&self, keys: &[String], f: &mut dyn FnMut(&str, Option<&Value>) -> Update) -> anyhow::Result<()> {
+        let mut map = self.inner.write().unwrap();
+        for key in keys {
+            match f(key, map.get(key)) {
+                Update::Keep => {}
+                Update::Set(record) => {
+                    map.insert(key.clone(), record);
+                }
+                Update::Remove => {
+                    map.remove(key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self) -> anyhow::Result<Vec<(String, Value)>> {
+        let map = self.inner.read().unwrap();
+        Ok(map.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    fn range(&self, start: Bound<&str>, end: Bound<&str>, limit: usize) -> anyhow::Result<Vec<(String, Value)>> {
+        // BTreeMap::range panics on inverted bounds
+        if is_empty_range(start, end) {
+            return Ok(Vec::new());
+        }
+        let map = self.inner.read().unwrap();
+        Ok(map
+            .range::<str, _>((start, end))
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn replace_all(&self, data: HashMap<String, Value>) -> anyhow::Result<()> {
+        let mut map = self.inner.write().unwrap();
+        *map = data.into_iter().collect();
+        Ok(())
+    }
+}
+
+fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e) | Bound::Included(e)) => s >= e,
+        _ => false,
+    }
+}
@@ -1,9 +1,19 @@
+pub mod crypto;
 pub mod engine;
+pub mod index;
 pub mod lamport;
+pub mod memory;
+pub mod sled_engine;
 pub mod snapshot;
 pub mod wal;
+pub mod writer;
 
-pub use engine::{Store, Value};
+pub use crypto::Keyring;
+pub use engine::{Store, Value, StorageEngine, Update, Checkpoint, AsOf, ReadAt};
+pub use index::{IndexDef, SecondaryIndex};
+pub use memory::MemoryEngine;
+pub use sled_engine::SledEngine;
 pub use lamport::{LamportClock};
-pub use wal::{Wal, replay_wal};
-pub use snapshot::{recover_from_snapshot_and_wal};
\ No newline at end of file
+pub use wal::{Wal, CommittedLog, WalOptions, WalPosition, Durability, replay_wal, read_wal, list_segments, check_wal, repair_torn_tail, migrate_legacy_wal, SegmentCheck};
+pub use snapshot::{Snapshot, SnapshotCfg, recover_from_snapshot_and_wal, recover_to, export_snapshot, install_snapshot, spawn_snapshotter, checkpoint};
+pub use writer::{WalWriter, Condition, CondOutcome, AtomicOp, AtomicOutcome, spawn_wal_writer};
\ No newline at end of file
@@ -0,0 +1,153 @@
+use std::{cell::RefCell, collections::HashMap, ops::Bound, path::Path};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use crate::store::{Checkpoint, StorageEngine, Update, Value};
+
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+
+// Persists the keyspace in an embedded sled database. A restart opens the data where it
+// was and only replays the WAL written after the last checkpoint.
+#[derive(Debug)]
+pub struct SledEngine {
+    db: sled::Db,
+    data: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl SledEngine {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = sled::open(path)?;
+        let data = db.open_tree("data")?;
+        let meta = db.open_tree("meta")?;
+        Ok(SledEngine { db, data, meta })
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Value> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+impl StorageEngine for SledEngine {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Value>> {
+        self.data.get(key)?.as_deref().map(Self::decode).transpose()
+    }
+
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<&Value>) -> Update) -> anyhow::Result<Option<Value>> {
+        loop {
+            let old = self.data.get(key)?;
+            let current = old.as_deref().map(Self::decode).transpose()?;
+            let new = match f(current.as_ref()) {
+                Update::Keep => return Ok(current),
+                Update::Set(record) => Some(serde_json::to_vec(&record)?),
+                Update::Remove => None,
+            };
+            // retry if someone else wrote the key in between
+            if self.data.compare_and_swap(key, old, new)?.is_ok() {
+                return Ok(current);
+            }
+        }
+    }
+
+    fn update_all(&self, keys: &[String], f: &mut dyn FnMut(&str, Option<&Value>) -> Update) -> anyhow::Result<()> {
+        // sled reruns the closure on conflict, and wants it to be Fn
+        let f = RefCell::new(f);
+        let result = self.data.transaction(|tx| {
+            let abort = |e: anyhow::Error| ConflictableTransactionError::Abort(e.to_string());
+            for key in keys {
+                let current = match tx.get(key)? {
+                    Some(old) => Some(Self::decode(&old).map_err(abort)?),
+                    None => None,
+                };
+                match (f.borrow_mut())(key, current.as_ref()) {
+                    Update::Keep => {}
+                    Update::Set(record) => {
+                        let bytes = serde_json::to_vec(&record).map_err(|e| abort(e.into()))?;
+                        tx.insert(key.as_bytes(), bytes)?;
+                    }
+                    Update::Remove => {
+                        tx.remove(key.as_bytes())?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(e)) => Err(anyhow::anyhow!(e)),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn scan(&self) -> anyhow::Result<Vec<(String, Value)>> {
+        let mut out = Vec::new();
+        for item in self.data.iter() {
+            let (k, v) = item?;
+            out.push((String::from_utf8(k.to_vec())?, Self::decode(&v)?));
+        }
+        Ok(out)
+    }
+
+    // sled orders keys bytewise, which matches the ordering of the UTF-8 strings
+    fn range(&self, start: Bound<&str>, end: Bound<&str>, limit: usize) -> anyhow::Result<Vec<(String, Value)>> {
+        let bounds = (start.map(str::as_bytes), end.map(str::as_bytes));
+        let mut out = Vec::new();
+        for item in self.data.range::<&[u8], _>(bounds).take(limit) {
+            let (k, v) = item?;
+            out.push((String::from_utf8(k.to_vec())?, Self::decode(&v)?));
+        }
+        Ok(out)
+    }
+
+    // one batch, so a crash leaves either the old keyspace or the new one
+    fn replace_all(&self, data: HashMap<String, Value>) -> anyhow::Result<()> {
+        let mut batch = sled::Batch::default();
+        for key in self.data.iter().keys() {
+            batch.remove(key?);
+        }
+        // a later insert of the same key takes the place of its remove
+        for (key, value) in data {
+            batch.insert(key.as_bytes(), serde_json::to_vec(&value)?);
+        }
+        self.data.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn persists_itself(&self) -> bool {
+        true
+    }
+
+    // flushing makes every write applied so far durable together with the checkpoint
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        self.meta.insert(CHECKPOINT_KEY, serde_json::to_vec(checkpoint)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn load_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        match self.meta.get(CHECKPOINT_KEY)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_all_swaps_the_whole_keyspace() {
+        let dir = std::env::temp_dir().join(format!("kv-sled-replace-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let value = |data: &str| Value { data: Some(data.as_bytes().to_vec()), ..Value::tombstone(1, 1) };
+
+        let engine = SledEngine::open(&dir).unwrap();
+        engine.replace_all(HashMap::from([("a".to_string(), value("1")), ("b".to_string(), value("2"))])).unwrap();
+        engine.replace_all(HashMap::from([("b".to_string(), value("3")), ("c".to_string(), value("4"))])).unwrap();
+
+        let keys: Vec<(String, Option<Vec<u8>>)> = engine.scan().unwrap().into_iter().map(|(k, v)| (k, v.data)).collect();
+        assert_eq!(keys, vec![("b".to_string(), Some(b"3".to_vec())), ("c".to_string(), Some(b"4".to_vec()))]);
+        drop(engine);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,27 +1,590 @@
-use crate::util::{LogEntry, Operation};
-use crate::store::{Store, Value, LamportClock, replay_wal};
+use std::{collections::HashMap, fs::{self, File}, io::Write, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
 
-// TODO: IMPLEMENT SNAPSHOTTING
+use crate::store::{Store, Value, Checkpoint, LamportClock, Wal, WalPosition, replay_wal, read_wal};
+use crate::store::crypto::Keyring;
+use crate::store::wal::{fsync_dir, segment_ids};
 
+const SNAPSHOT_EXT: &str = "snap";
+// an encrypted snapshot is this magic followed by the JSON sealed with the keyring, bound to
+// the file name; a plaintext one is just the JSON
+const SEALED_MAGIC: &[u8; 4] = b"RKVS";
+const SNAPSHOTS_RETAINED: usize = 2;
+const CHECK_EVERY_MS: u64 = 1000;
+
+#[derive(Clone, Debug)]
+pub struct SnapshotCfg {
+    pub dir: String,
+    pub interval: Duration,     // how often to checkpoint without forcing a new WAL segment
+    pub compact_wal_bytes: u64, // compact once this many WAL bytes were written since the last compaction (0 = never)
+    pub compact_wal_entries: u64, // or once this many records were appended (0 = never)
+    pub wal_retain_segments: u64, // checkpointed WAL segments to keep around anyway, e.g. for archiving
+    pub keys: Option<Keyring>, // seal snapshots, and re-seal retained WAL segments on compaction
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub lamport_hw: u64,  // clock value at the time of the snapshot
+    #[serde(default)]
+    pub wal_position: WalPosition, // WAL entries before this position are already reflected in `data`
+    pub data: HashMap<String, Value>,
+}
+
+// writes the snapshot atomically: temp file, fsync, rename, fsync dir
+pub fn write_snapshot<P: AsRef<Path>>(dir: P, snapshot: &Snapshot, keys: Option<&Keyring>) -> anyhow::Result<PathBuf> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let pos = snapshot.wal_position;
+    let name = format!("snapshot-{:020}-{:020}.{}", pos.segment, pos.offset, SNAPSHOT_EXT);
+    let final_path = dir.join(&name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+
+    {
+        let json = serde_json::to_vec(snapshot)?;
+        let mut file = File::create(&tmp_path)?;
+        match keys {
+            Some(keys) => {
+                file.write_all(SEALED_MAGIC)?;
+                file.write_all(&keys.seal(&json, name.as_bytes()))?;
+            }
+            None => file.write_all(&json)?,
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, &final_path)?;
+    fsync_dir(dir)?;
+
+    // keep a couple of older snapshots around in case the newest can't be read
+    prune_snapshots(dir, SNAPSHOTS_RETAINED)?;
+
+    Ok(final_path)
+}
+
+// removes all but the `keep` newest snapshots
+fn prune_snapshots(dir: &Path, keep: usize) -> anyhow::Result<()> {
+    let snapshots = list_snapshots(dir)?;
+    if snapshots.len() > keep {
+        for old in &snapshots[..snapshots.len() - keep] {
+            fs::remove_file(old)?;
+        }
+    }
+    Ok(())
+}
+
+// snapshot files in the directory, oldest first
+fn list_snapshots(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(SNAPSHOT_EXT) {
+            paths.push(path);
+        }
+    }
+    // names embed the zero-padded WAL position, so lexical order is age order
+    paths.sort();
+    Ok(paths)
+}
+
+// WAL position encoded in a snapshot file name
+fn snapshot_position(path: &Path) -> Option<WalPosition> {
+    let stem = path.file_stem()?.to_str()?.strip_prefix("snapshot-")?;
+    let (segment, offset) = stem.split_once('-')?;
+    Some(WalPosition { segment: segment.parse().ok()?, offset: offset.parse().ok()? })
+}
+
+// oldest WAL segment any retained snapshot still needs
+fn oldest_snapshot_segment(dir: &str) -> anyhow::Result<Option<u64>> {
+    Ok(list_snapshots(Path::new(dir))?
+        .first()
+        .and_then(|p| snapshot_position(p))
+        .map(|pos| pos.segment))
+}
+
+// Newest snapshot that can be read, if any. One that can't be parsed is only passed over for
+// an older one whose WAL segments are all still on disk, or for none at all if the WAL was
+// never trimmed: compaction removes the segments before the newest snapshot, and resuming
+// without them would silently drop every write in between. One that fails to decrypt is
+// always an error.
+pub fn load_latest_snapshot<P: AsRef<Path>, Q: AsRef<Path>>(
+    dir: P,
+    wal_dir: Q,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<Option<Snapshot>> {
+    let first_segment = segment_ids(wal_dir.as_ref())?.first().copied();
+    let mut unreadable: Option<PathBuf> = None;
+    for path in list_snapshots(dir.as_ref())?.into_iter().rev() {
+        if let Some(newer) = &unreadable
+            && snapshot_position(&path).is_none_or(|pos| first_segment.is_none_or(|first| pos.segment < first))
+        {
+            anyhow::bail!(
+                "snapshot {} can't be read and the WAL no longer reaches back to {}",
+                newer.display(), path.display()
+            );
+        }
+        match serde_json::from_slice::<Snapshot>(&snapshot_bytes(&path, keys)?) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(e) => {
+                eprintln!("Error reading snapshot {}: {}. Trying an older one.", path.display(), e);
+                unreadable = Some(path);
+            }
+        }
+    }
+    if let Some(newer) = unreadable && first_segment != Some(1) {
+        anyhow::bail!("snapshot {} can't be read and the WAL no longer starts at segment 1", newer.display());
+    }
+    Ok(None)
+}
+
+// the snapshot's JSON, decrypted if it was sealed
+fn snapshot_bytes(path: &Path, keys: Option<&Keyring>) -> anyhow::Result<Vec<u8>> {
+    let bytes = fs::read(path)?;
+    let Some(sealed) = bytes.strip_prefix(SEALED_MAGIC) else {
+        if keys.is_some_and(|k| !k.accepts_plaintext()) {
+            anyhow::bail!("snapshot {} is not encrypted; pass --accept-plaintext if it predates encryption", path.display());
+        }
+        return Ok(bytes);
+    };
+    let Some(keys) = keys else {
+        anyhow::bail!("snapshot {} is encrypted and no key is loaded", path.display());
+    };
+    let name = path.file_name().unwrap().to_string_lossy();
+    keys.open(sealed, name.as_bytes())
+        .map_err(|e| anyhow::anyhow!("snapshot {} can't be decrypted: {}", path.display(), e))
+}
+
+// Returns the checkpoint recovery resumed from; the reopened WAL must not go below its position.
+// An engine that keeps its own checkpoint already holds the data; otherwise the newest
+// snapshot is loaded into it.
 pub async fn recover_from_snapshot_and_wal(
-    store: &mut Store,
+    store: &Store,
     clock: &LamportClock,
-    snapshot_path: &str,
-    wal_path: &str,
-) -> anyhow::Result<()> {
-    let entries = replay_wal(wal_path)?;
+    snapshot_dir: &str,
+    wal_dir: &str,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<Checkpoint> {
+    let mut resumed = Checkpoint { lamport_hw: 0, wal_position: WalPosition::default() };
+    if let Some(checkpoint) = store.load_checkpoint()? {
+        println!(
+            "Resuming from engine checkpoint: lamport_hw={}, wal_position={:?}",
+            checkpoint.lamport_hw, checkpoint.wal_position
+        );
+        clock.tick_observe(checkpoint.lamport_hw);
+        resumed = checkpoint;
+    } else if let Some(snapshot) = load_latest_snapshot(snapshot_dir, wal_dir, keys)? {
+        println!(
+            "Loaded snapshot: lamport_hw={}, wal_position={:?}, keys={}",
+            snapshot.lamport_hw, snapshot.wal_position, snapshot.data.len()
+        );
+        clock.tick_observe(snapshot.lamport_hw);
+        resumed = Checkpoint { lamport_hw: snapshot.lamport_hw, wal_position: snapshot.wal_position };
+        store.restore(snapshot.data)?;
+    }
+
+    let entries = replay_wal(wal_dir, resumed.wal_position, keys)?;
     for entry in entries {
-        println!("{:?}", entry);
         clock.tick_observe(entry.ts);
-        match entry.operation {
-            Operation::Put { key, value } => {
-                store.put(key, value, entry.ts, entry.node_id).await;
+        store.apply(entry)?;
+    }
+    store.reindex_expiries()?;
+    store.rebuild_indexes()?;
+
+    Ok(resumed)
+}
+
+// Point-in-time recovery: rebuilds `store`, which must be empty, as it was once every write
+// stamped at or before `until_ts` had been applied and nothing later. Starts from the newest
+// snapshot taken by then whose WAL is still on disk, or from the beginning of the WAL if it
+// was never trimmed, and replays only the entries stamped up to `until_ts`. Replication
+// doesn't deliver entries in timestamp order, so later ones are skipped rather than ending
+// the replay. Nothing on disk is modified.
+pub async fn recover_to(
+    store: &Store,
+    clock: &LamportClock,
+    snapshot_dir: &str,
+    wal_dir: &str,
+    keys: Option<&Keyring>,
+    until_ts: u64,
+) -> anyhow::Result<Checkpoint> {
+    let first_segment = segment_ids(Path::new(wal_dir))?.first().copied().unwrap_or(1);
+    let mut base = None;
+    for path in list_snapshots(Path::new(snapshot_dir))?.into_iter().rev() {
+        if snapshot_position(&path).is_none_or(|pos| pos.segment < first_segment) {
+            // the segments after it are gone, and so are those of anything older
+            break;
+        }
+        let snapshot = serde_json::from_slice::<Snapshot>(&snapshot_bytes(&path, keys)?)
+            .map_err(|e| anyhow::anyhow!("snapshot {} can't be read: {}", path.display(), e))?;
+        if snapshot.lamport_hw <= until_ts {
+            base = Some(snapshot);
+            break;
+        }
+    }
+
+    let resumed = match base {
+        Some(snapshot) => {
+            println!(
+                "Recovering from snapshot: lamport_hw={}, wal_position={:?}, keys={}",
+                snapshot.lamport_hw, snapshot.wal_position, snapshot.data.len()
+            );
+            clock.tick_observe(snapshot.lamport_hw);
+            store.restore(snapshot.data)?;
+            Checkpoint { lamport_hw: snapshot.lamport_hw, wal_position: snapshot.wal_position }
+        }
+        None if first_segment <= 1 => Checkpoint { lamport_hw: 0, wal_position: WalPosition::default() },
+        None => anyhow::bail!(
+            "no snapshot at or before ts {} and WAL segments before {} are gone; recovery can't reach back that far",
+            until_ts, first_segment
+        ),
+    };
+
+    let mut skipped = 0;
+    for entry in read_wal(wal_dir, resumed.wal_position, keys)? {
+        if entry.ts > until_ts {
+            skipped += 1;
+            continue;
+        }
+        clock.tick_observe(entry.ts);
+        store.apply(entry)?;
+    }
+    store.reindex_expiries()?;
+    store.rebuild_indexes()?;
+    println!("Recovered to ts {}, skipping {} later WAL entries", until_ts, skipped);
+
+    Ok(resumed)
+}
+
+// Writes the store as the only snapshot of a new snapshot directory, positioned at the very
+// start of the WAL, so a node pointed at it with an empty WAL directory starts from exactly
+// this state.
+pub fn export_snapshot(store: &Store, clock: &LamportClock, dir: &str, keys: Option<&Keyring>) -> anyhow::Result<PathBuf> {
+    if !list_snapshots(Path::new(dir))?.is_empty() {
+        anyhow::bail!("{} already holds snapshots", dir);
+    }
+    let snapshot = Snapshot {
+        lamport_hw: clock.tick_now(),
+        wal_position: WalPosition::default(),
+        data: store.dump()?,
+    };
+    write_snapshot(dir, &snapshot, keys)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckpointStats {
+    pub lamport_hw: u64,
+    pub wal_position: WalPosition,
+    pub wal_bytes_before: u64,
+    pub wal_entries_before: u64,
+    pub segments_removed: usize,
+    pub segments_resealed: usize,
+}
+
+// Records the store and clock as of the current WAL position, then drops the WAL segments
+// that are no longer needed. The position and the state are captured together under the WAL
+// lock (the WAL writer applies to the store while holding it); persisting happens after the
+// lock is released. A compaction first seals the current segment so the checkpoint covers
+// every earlier one in full, and keeps only the new snapshot. A crash before the old segments
+// are removed only leaves files behind that replay will skip. With encryption, a compaction
+// also re-seals the segments it keeps under the current key, so after a key rotation nothing
+// on disk needs the older keys any more.
+pub async fn checkpoint(
+    store: &Store,
+    clock: &LamportClock,
+    wal: &Mutex<Wal>,
+    cfg: &SnapshotCfg,
+    compacting: bool,
+) -> anyhow::Result<CheckpointStats> {
+    let (checkpoint, snapshot_data, wal_bytes_before, wal_entries_before) = {
+        let mut wal = wal.lock().await;
+        let before = (wal.bytes(), wal.entries());
+        if compacting {
+            wal.rotate()?;
+        }
+        let checkpoint = Checkpoint { lamport_hw: clock.tick_now(), wal_position: wal.position() };
+        // an engine that persists itself only needs to flush, which can happen later:
+        // it will then hold everything up to this position and possibly a bit more
+        let data = if store.persists_itself() { None } else { Some(store.dump()?) };
+        (checkpoint, data, before.0, before.1)
+    };
+
+    let keep_from = match snapshot_data {
+        None => {
+            store.save_checkpoint(&checkpoint)?;
+            Some(checkpoint.wal_position.segment)
+        }
+        Some(data) => {
+            let snapshot = Snapshot {
+                lamport_hw: checkpoint.lamport_hw,
+                wal_position: checkpoint.wal_position,
+                data,
+            };
+            let dir = cfg.dir.clone();
+            let keys = cfg.keys.clone();
+            let path = tokio::task::spawn_blocking(move || write_snapshot(dir, &snapshot, keys.as_ref())).await??;
+            println!("Wrote snapshot {}", path.display());
+            if compacting {
+                // older snapshots would pin the segments we are trying to drop
+                prune_snapshots(Path::new(&cfg.dir), 1)?;
             }
-            Operation::Delete { key } => {
-                store.delete(&key, entry.ts, entry.node_id).await;
+            oldest_snapshot_segment(&cfg.dir)?
+        }
+    };
+
+    let (segments_removed, segments_resealed) = match keep_from {
+        Some(segment) => {
+            let mut wal = wal.lock().await;
+            let removed = wal.remove_segments_before(segment.saturating_sub(cfg.wal_retain_segments))?;
+            if removed > 0 {
+                wal.mark_pruned(checkpoint.lamport_hw);
             }
+            let resealed = if compacting { wal.reseal_segments_before(segment)? } else { 0 };
+            (removed, resealed)
+        }
+        None => (0, 0),
+    };
+
+    Ok(CheckpointStats {
+        lamport_hw: checkpoint.lamport_hw,
+        wal_position: checkpoint.wal_position,
+        wal_bytes_before,
+        wal_entries_before,
+        segments_removed,
+        segments_resealed,
+    })
+}
+
+// Makes `archive` the state of a node that holds no data, durable before it is loaded: if
+// anything fails the node is left as empty on disk as in memory. It is checkpointed at the
+// WAL's current position, so a restart loads it and replays only what is written after. The
+// caller holds the WAL lock and passes what it guards.
+pub async fn install_snapshot(
+    store: &Store,
+    clock: &LamportClock,
+    wal: &Wal,
+    cfg: &SnapshotCfg,
+    archive: Snapshot,
+) -> anyhow::Result<()> {
+    // the node's own writes order after everything in the archive
+    clock.tick_observe(archive.lamport_hw);
+    let checkpoint = Checkpoint { lamport_hw: clock.tick_now(), wal_position: wal.position() };
+
+    if store.persists_itself() {
+        // the engine's copy is only trusted once the checkpoint next to it says so
+        let installed = store.restore(archive.data).and_then(|_| store.save_checkpoint(&checkpoint));
+        if let Err(e) = installed {
+            store.restore(HashMap::new())?;
+            return Err(e);
+        }
+    } else {
+        let snapshot = Snapshot { lamport_hw: checkpoint.lamport_hw, wal_position: checkpoint.wal_position, data: archive.data };
+        let (dir, keys) = (cfg.dir.clone(), cfg.keys.clone());
+        let (path, snapshot) = tokio::task::spawn_blocking(move || {
+            write_snapshot(dir, &snapshot, keys.as_ref()).map(|path| (path, snapshot))
+        }).await??;
+        if let Err(e) = store.restore(snapshot.data) {
+            store.restore(HashMap::new())?;
+            fs::remove_file(&path)?;
+            return Err(e);
         }
     }
+    store.reindex_expiries()
+}
 
-    Ok(())
-}
\ No newline at end of file
+// periodically checkpoints the store whenever the WAL has grown since the last one,
+// and compacts the WAL once it crosses the configured size or entry count
+pub fn spawn_snapshotter(
+    store: Arc<Store>,
+    clock: Arc<LamportClock>,
+    wal: Arc<Mutex<Wal>>,
+    cfg: SnapshotCfg,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(CHECK_EVERY_MS));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut last_checkpoint = Instant::now();
+        let mut last_position = None;
+
+        loop {
+            ticker.tick().await;
+
+            let (position, bytes, entries) = {
+                let wal = wal.lock().await;
+                (wal.position(), wal.bytes(), wal.entries())
+            };
+            let over_bytes = cfg.compact_wal_bytes > 0 && bytes >= cfg.compact_wal_bytes;
+            let over_entries = cfg.compact_wal_entries > 0 && entries >= cfg.compact_wal_entries;
+            let due = last_checkpoint.elapsed() >= cfg.interval && last_position != Some(position);
+            if !over_bytes && !over_entries && !due {
+                continue;
+            }
+
+            let compacting = over_bytes || over_entries;
+            match checkpoint(&store, &clock, &wal, &cfg, compacting).await {
+                Ok(stats) => {
+                    if compacting {
+                        println!(
+                            "Compacted WAL: {} bytes, {} entries, {} segments removed, {} re-sealed",
+                            stats.wal_bytes_before, stats.wal_entries_before, stats.segments_removed, stats.segments_resealed
+                        );
+                    }
+                    last_position = Some(stats.wal_position);
+                }
+                Err(e) => eprintln!("Checkpoint failed: {}", e),
+            }
+            last_checkpoint = Instant::now();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Durability, WalOptions};
+    use crate::util::{LogEntry, Operation};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kv-snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn snapshot_at(dir: &Path, segment: u64, lamport_hw: u64) -> PathBuf {
+        let snapshot = Snapshot { lamport_hw, wal_position: WalPosition { segment, offset: 8 }, data: HashMap::new() };
+        write_snapshot(dir, &snapshot, None).unwrap()
+    }
+
+    // an empty segment file, enough for recovery to see which segments exist
+    fn segment(wal_dir: &Path, id: u64) {
+        fs::create_dir_all(wal_dir).unwrap();
+        fs::write(wal_dir.join(format!("{:020}.wal", id)), b"").unwrap();
+    }
+
+    #[test]
+    fn falls_back_only_to_a_snapshot_the_wal_still_covers() {
+        let dir = temp_dir("fallback");
+        let (snaps, wal) = (dir.join("snapshots"), dir.join("wal"));
+        snapshot_at(&snaps, 2, 10);
+        let newest = snapshot_at(&snaps, 3, 20);
+        fs::write(&newest, b"{").unwrap();
+
+        segment(&wal, 2);
+        segment(&wal, 3);
+        assert_eq!(load_latest_snapshot(&snaps, &wal, None).unwrap().unwrap().lamport_hw, 10);
+
+        // compaction removed the segment the older snapshot needs
+        fs::remove_file(wal.join(format!("{:020}.wal", 2))).unwrap();
+        assert!(load_latest_snapshot(&snaps, &wal, None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn put(ts: u64, key: &str, value: &str) -> LogEntry {
+        let operation = Operation::Put { key: key.to_string(), value: value.as_bytes().to_vec(), content_type: None, expires_at: None, context: None };
+        LogEntry { ts, node_id: 1, operation }
+    }
+
+    // appends the entries and applies them to `store`, returning where the WAL ends
+    fn log(wal_dir: &Path, store: &Store, entries: Vec<LogEntry>) -> WalPosition {
+        let opts = WalOptions { segment_bytes: 1 << 20, durability: Durability::Os, fsync_seconds: None, keys: None };
+        let mut wal = Wal::open(wal_dir, opts, 1).unwrap();
+        for entry in entries {
+            wal.append(&entry).unwrap();
+            store.apply(entry).unwrap();
+        }
+        wal.commit().unwrap();
+        wal.position()
+    }
+
+    fn data_at(store: &Store, key: &str) -> Option<Vec<u8>> {
+        store.get(key).unwrap().and_then(|v| v.data)
+    }
+
+    fn cfg(dir: &Path) -> SnapshotCfg {
+        SnapshotCfg {
+            dir: dir.to_str().unwrap().to_string(),
+            interval: Duration::from_secs(60),
+            compact_wal_bytes: 0,
+            compact_wal_entries: 0,
+            wal_retain_segments: 0,
+            keys: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_restored_archive_survives_a_restart_with_later_writes_on_top() {
+        let dir = temp_dir("restore");
+        let (snaps, wal_dir) = (dir.join("snapshots"), dir.join("wal"));
+        let source = Store::new();
+        source.apply(put(5, "a", "1")).unwrap();
+        source.apply(put(6, "b", "2")).unwrap();
+        let archive = Snapshot { lamport_hw: 6, wal_position: WalPosition::default(), data: source.dump().unwrap() };
+
+        let opts = WalOptions { segment_bytes: 1 << 20, durability: Durability::Os, fsync_seconds: None, keys: None };
+        let mut wal = Wal::open(&wal_dir, opts, 1).unwrap();
+        let (store, clock) = (Store::new(), LamportClock::new());
+        install_snapshot(&store, &clock, &wal, &cfg(&snaps), archive).await.unwrap();
+        assert!(clock.tick_now() >= 6);
+        assert_eq!(data_at(&store, "a"), Some(b"1".to_vec()));
+        wal.append(&put(clock.tick_send(), "b", "3")).unwrap();
+        wal.commit().unwrap();
+        drop(wal);
+
+        let restarted = Store::new();
+        recover_from_snapshot_and_wal(&restarted, &LamportClock::new(), snaps.to_str().unwrap(), wal_dir.to_str().unwrap(), None).await.unwrap();
+        assert_eq!(data_at(&restarted, "a"), Some(b"1".to_vec()));
+        assert_eq!(data_at(&restarted, "b"), Some(b"3".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovers_up_to_the_timestamp_and_no_further() {
+        let dir = temp_dir("pitr");
+        let (snaps, wal) = (dir.join("snapshots"), dir.join("wal"));
+        let live = Store::new();
+        log(&wal, &live, vec![put(1, "a", "1"), put(2, "b", "2"), put(3, "a", "3")]);
+        let (snaps_str, wal_str) = (snaps.to_str().unwrap(), wal.to_str().unwrap());
+
+        // with no snapshot the whole WAL is replayed, up to and including the target
+        let store = Store::new();
+        let from = recover_to(&store, &LamportClock::new(), snaps_str, wal_str, None, 2).await.unwrap();
+        assert_eq!(from.lamport_hw, 0);
+        assert_eq!(data_at(&store, "a"), Some(b"1".to_vec()));
+        assert_eq!(data_at(&store, "b"), Some(b"2".to_vec()));
+
+        let store = Store::new();
+        recover_to(&store, &LamportClock::new(), snaps_str, wal_str, None, 0).await.unwrap();
+        assert_eq!(data_at(&store, "a"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_from_the_newest_snapshot_at_or_before_the_timestamp() {
+        let dir = temp_dir("pitr-snapshot");
+        let (snaps, wal) = (dir.join("snapshots"), dir.join("wal"));
+        let live = Store::new();
+        let at_2 = log(&wal, &live, vec![put(1, "a", "1"), put(2, "b", "2")]);
+        write_snapshot(&snaps, &Snapshot { lamport_hw: 2, wal_position: at_2, data: live.dump().unwrap() }, None).unwrap();
+        let at_4 = log(&wal, &live, vec![put(3, "a", "3"), put(4, "c", "4")]);
+        write_snapshot(&snaps, &Snapshot { lamport_hw: 4, wal_position: at_4, data: live.dump().unwrap() }, None).unwrap();
+        log(&wal, &live, vec![put(5, "b", "5")]);
+        let (snaps_str, wal_str) = (snaps.to_str().unwrap(), wal.to_str().unwrap());
+
+        // the snapshot at 4 holds writes past 3, so recovery to 3 has to start from the one at 2
+        let store = Store::new();
+        let from = recover_to(&store, &LamportClock::new(), snaps_str, wal_str, None, 3).await.unwrap();
+        assert_eq!(from.lamport_hw, 2);
+        assert_eq!(data_at(&store, "a"), Some(b"3".to_vec()));
+        assert_eq!(data_at(&store, "c"), None);
+
+        let store = Store::new();
+        let from = recover_to(&store, &LamportClock::new(), snaps_str, wal_str, None, 4).await.unwrap();
+        assert_eq!(from.lamport_hw, 4);
+        assert_eq!(data_at(&store, "b"), Some(b"2".to_vec()));
+        assert_eq!(data_at(&store, "c"), Some(b"4".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,88 +1,705 @@
-use crate::util::{LogEntry, Operation};
-use std::{fs::{File, OpenOptions}, io::{BufReader, BufRead, BufWriter, Write, self}, path::{Path, PathBuf}};
+use crate::store::crypto::{Keyring, SEALED_OVERHEAD, sealed_key_id};
+use crate::util::LogEntry;
+use prometheus::Histogram;
+use serde::{Serialize, Deserialize};
+use std::{collections::BTreeMap, fs::{self, File, OpenOptions}, io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write, self}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+// The WAL is a directory of numbered segment files (`00000000000000000001.wal`, ...).
+// Each segment is an 8 byte header (magic + format version) followed by records of
+//   [len: u32 LE][crc32(payload): u32 LE][payload: JSON-encoded LogEntry]
+// In an encrypted segment (format v2) each payload is the JSON sealed with the keyring,
+// bound to the segment id and the record's offset.
+const WAL_MAGIC: &[u8; 4] = b"RKVW";
+const WAL_VERSION: u32 = 1;
+const WAL_VERSION_SEALED: u32 = 2;
+const WAL_HEADER_LEN: u64 = 8;
+const RECORD_HEADER_LEN: u64 = 8;
+const MAX_RECORD_LEN: u64 = 16 * 1024 * 1024;
+const SEGMENT_EXT: &str = "wal";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WalError {
+    #[error("WAL segment {path} has an unrecognised header (expected format v{WAL_VERSION} or v{WAL_VERSION_SEALED})")]
+    BadHeader { path: String },
+    #[error("WAL segment {path} is corrupt at offset {offset}: {reason}")]
+    Corrupt { path: String, offset: u64, reason: String },
+    #[error("WAL segment {path} can't be decrypted at offset {offset}: {reason}")]
+    Undecryptable { path: String, offset: u64, reason: String },
+}
+
+// a point in the WAL; orders by segment first, then by offset within it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WalPosition {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+// when committed records are forced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    Always,             // fsync before acknowledging every write
+    Interval(Duration), // fsync in the background at most this long after a write
+    Os,                 // hand records to the OS and let it decide when to flush
+}
+
+impl Durability {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Durability::Always => "always",
+            Durability::Interval(_) => "interval",
+            Durability::Os => "os",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    pub segment_bytes: u64, // roll over to a new segment once the current one reaches this size
+    pub durability: Durability,
+    pub fsync_seconds: Option<Histogram>, // observes the latency of every fsync
+    pub keys: Option<Keyring>, // seal new records with the current key
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentInfo {
+    pub segment: u64,
+    pub bytes: u64,
+    pub entries: u64,
+    pub encrypted: bool,
+    pub first_ts: Option<u64>,
+    pub last_ts: Option<u64>,
+}
+
+// A fixed extent of the WAL: the segments up to `end`, read from disk without the WAL lock.
+// Compaction may still remove the older segments meanwhile; reading one then fails.
+#[derive(Debug, Clone)]
+pub struct CommittedLog {
+    dir: PathBuf,
+    keys: Option<Keyring>,
+    segments: Vec<u64>,
+    end: WalPosition,
+    max_ts: Arc<Mutex<BTreeMap<u64, u64>>>,
+}
+
+impl CommittedLog {
+    // Hands each entry stamped after `ts` to `visit`, in log order, until it returns false.
+    // Blocking; segments known to hold nothing after `ts` are skipped without being read.
+    pub fn read_since(&self, ts: u64, mut visit: impl FnMut(LogEntry) -> bool) -> anyhow::Result<()> {
+        let mut open = true;
+        for &id in &self.segments {
+            let sealed = id < self.end.segment;
+            if sealed && self.max_ts.lock().unwrap().get(&id).is_some_and(|&max| max <= ts) {
+                continue;
+            }
+            let until = if sealed { u64::MAX } else { self.end.offset };
+            let mut max = 0;
+            scan_range(&self.dir, id, 0, until, self.keys.as_ref(), |_, e, _| {
+                max = max.max(e.ts);
+                if open && e.ts > ts {
+                    open = visit(e);
+                }
+            })?;
+            if !open {
+                return Ok(());
+            }
+            if sealed {
+                self.max_ts.lock().unwrap().insert(id, max);
+            }
+        }
+        Ok(())
+    }
+}
+
+// where the WAL stood before a group of appends, so they can be undone together
+#[derive(Debug, Clone, Copy)]
+pub struct WalMark {
+    segment: u64,
+    offset: u64,
+    entries: u64,
+    bytes: u64,
+}
 
 #[derive(Debug)]
 pub struct Wal {
-    path: PathBuf,
-    writer: BufWriter<File>,
+    dir: PathBuf,
+    opts: WalOptions,
+    segment: u64,
+    writer: Option<BufWriter<File>>, // None if opened read-only
+    offset: u64, // byte offset of the end of the last appended record in the current segment
+    entries: u64, // records appended since the WAL was opened or last rotated for a checkpoint
+    bytes: u64,   // bytes appended over the same period
+    dirty: bool,  // records were written since the last fsync
+    pruned_hw: u64, // entries stamped at or below this may be gone with removed segments
+    poisoned: Option<String>, // a failed rollback left records nobody was acknowledged for
+    max_ts: Arc<Mutex<BTreeMap<u64, u64>>>, // segment -> highest ts in it, where known
 }
 
 impl Wal {
-    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let path = path.as_ref().to_path_buf();
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-
-        let create_new = !path.exists();
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true) 
-            .open(&path)?;
-
-        // If file was just created, fsync the directory so the entry itself is durable.
-        if create_new {
-            if let Some(parent) = path.parent() {
-                fsync_dir(parent)?;
-            }
+    // `min_segment` is the segment recovery resumed from; appends must never land in a
+    // segment before it, or the next recovery would skip them
+    pub fn open<P: AsRef<Path>>(dir: P, opts: WalOptions, min_segment: u64) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let last = segment_ids(&dir)?.last().copied().unwrap_or(1);
+        let mut segment = last.max(min_segment);
+        let sealed = opts.keys.is_some();
+        if segment_format(&dir, segment)?.is_some_and(|s| s != sealed) {
+            // encryption was switched on or off: start a segment in the new format
+            // rather than mixing records of both in one
+            segment += 1;
         }
+        let (file, offset) = open_segment(&dir, segment, sealed)?;
+        Ok(Self { dir, opts, segment, writer: Some(BufWriter::new(file)), offset, entries: 0, bytes: 0, dirty: false, pruned_hw: 0, poisoned: None, max_ts: Arc::default() })
+    }
+
+    // The WAL as it stands, for a node serving a point-in-time recovery: it can be read and
+    // backed up, but nothing is created or written, and appends fail.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P, opts: WalOptions) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let last = if dir.is_dir() { segment_ids(&dir)?.last().copied() } else { None };
+        let segment = last.unwrap_or(1);
+        let offset = match last {
+            Some(id) => fs::metadata(segment_path(&dir, id))?.len(),
+            None => WAL_HEADER_LEN,
+        };
+        Ok(Self { dir, opts, segment, writer: None, offset, entries: 0, bytes: 0, dirty: false, pruned_hw: 0, poisoned: None, max_ts: Arc::default() })
+    }
 
-        let writer = BufWriter::new(file);
-        Ok(Self { path, writer })
+    // the current segment's writer, unless the WAL is read-only
+    fn writer(&mut self) -> io::Result<&mut BufWriter<File>> {
+        self.writer.as_mut().ok_or_else(|| io::Error::other("the WAL is open read-only"))
     }
 
     pub fn append(&mut self, entry: &LogEntry) -> anyhow::Result<()> {
-        let line = serde_json::to_string(entry)?;
-        self.writer.write_all(line.as_bytes())?;
-        self.writer.write_all(b"\n")?;
+        self.check_poisoned()?;
+        let json = serde_json::to_vec(entry)?;
+        // replay would take a longer record for a damaged length and refuse to start
+        let payload_len = json.len() + if self.opts.keys.is_some() { SEALED_OVERHEAD } else { 0 };
+        if payload_len as u64 > MAX_RECORD_LEN {
+            anyhow::bail!("entry of {} bytes is over the WAL's {} byte record limit", payload_len, MAX_RECORD_LEN);
+        }
+        if self.offset >= self.opts.segment_bytes && self.offset > WAL_HEADER_LEN {
+            self.roll()?;
+        }
+
+        let payload = match &self.opts.keys {
+            Some(keys) => keys.seal(&json, &record_aad(self.segment, self.offset)),
+            None => json,
+        };
+        let written = write_record(self.writer()?, &payload)?;
+        let mut max_ts = self.max_ts.lock().unwrap();
+        let max = max_ts.entry(self.segment).or_default();
+        *max = (*max).max(entry.ts);
+        drop(max_ts);
+        self.offset += written;
+        self.bytes += written;
+        self.entries += 1;
+        self.dirty = true;
         Ok(())
     }
 
     pub fn sync(&mut self) -> io::Result<()> {
-        self.writer.flush();
-        self.writer.get_mut().sync_all()
+        self.check_poisoned()?;
+        let writer = self.writer()?;
+        writer.flush()?;
+        let started = Instant::now();
+        writer.get_mut().sync_all()?;
+        if let Some(hist) = &self.opts.fsync_seconds {
+            hist.observe(started.elapsed().as_secs_f64());
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    // makes appended records as durable as the configured policy asks for
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.check_poisoned()?;
+        match self.opts.durability {
+            Durability::Always => self.sync(),
+            Durability::Interval(_) | Durability::Os => self.writer()?.flush(),
+        }
+    }
+
+    pub fn mark(&self) -> WalMark {
+        WalMark { segment: self.segment, offset: self.offset, entries: self.entries, bytes: self.bytes }
+    }
+
+    // Undoes every append since `mark` after they failed to commit: drops what is still
+    // buffered, cuts the segment back to the mark and removes segments rolled into since, so
+    // none of those records can be replayed later. If that fails too, the WAL refuses all
+    // further writes rather than append after records that may or may not survive.
+    pub fn rollback(&mut self, mark: WalMark) -> anyhow::Result<()> {
+        let undone = self.truncate_to(mark);
+        if let Err(e) = &undone {
+            self.poisoned = Some(e.to_string());
+        }
+        undone
+    }
+
+    fn truncate_to(&mut self, mark: WalMark) -> anyhow::Result<()> {
+        let file = OpenOptions::new().append(true).read(true).open(segment_path(&self.dir, mark.segment))?;
+        // into_parts hands back the unwritten buffer instead of flushing it
+        let _ = self.writer.replace(BufWriter::new(file)).map(BufWriter::into_parts);
+        self.segment = mark.segment;
+
+        for id in segment_ids(&self.dir)? {
+            if id > mark.segment {
+                fs::remove_file(segment_path(&self.dir, id))?;
+                self.max_ts.lock().unwrap().remove(&id);
+            }
+        }
+        let file = self.writer()?.get_ref();
+        file.set_len(mark.offset)?;
+        file.sync_all()?;
+        fsync_dir(&self.dir)?;
+
+        self.offset = mark.offset;
+        self.entries = mark.entries;
+        self.bytes = mark.bytes;
+        Ok(())
+    }
+
+    fn check_poisoned(&self) -> io::Result<()> {
+        match &self.poisoned {
+            Some(reason) => Err(io::Error::other(format!("WAL is unusable after a failed rollback: {}", reason))),
+            None => Ok(()),
+        }
+    }
+
+    // background half of Durability::Interval
+    pub fn sync_if_dirty(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.opts.durability
     }
 
-    pub fn append_sync(&mut self, entry: &LogEntry) -> anyhow::Result<()> {
-        self.append(entry)?;
+    // seals the current segment and starts an empty one, so a checkpoint taken right after
+    // covers every earlier segment in full
+    pub fn rotate(&mut self) -> anyhow::Result<()> {
+        if self.offset > WAL_HEADER_LEN {
+            self.roll()?;
+        }
+        self.entries = 0;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    fn roll(&mut self) -> anyhow::Result<()> {
+        // the old segment must be durable before anything lands in the next one,
+        // otherwise replay could find a torn record in the middle of the log
         self.sync()?;
+        let (file, offset) = open_segment(&self.dir, self.segment + 1, self.opts.keys.is_some())?;
+        self.segment += 1;
+        self.writer = Some(BufWriter::new(file));
+        self.offset = offset;
         Ok(())
     }
 
-    pub fn path(&self) -> &Path {
-        &self.path
+    // deletes segments that lie wholly before `segment`; callers must only pass a segment
+    // that a durable snapshot already covers
+    pub fn remove_segments_before(&mut self, segment: u64) -> anyhow::Result<usize> {
+        self.writer()?;
+        let mut removed = 0;
+        for id in segment_ids(&self.dir)? {
+            if id >= segment || id >= self.segment {
+                break;
+            }
+            fs::remove_file(segment_path(&self.dir, id))?;
+            self.max_ts.lock().unwrap().remove(&id);
+            removed += 1;
+        }
+        if removed > 0 {
+            fsync_dir(&self.dir)?;
+        }
+        Ok(removed)
+    }
+
+    // Rewrites the segments wholly before `segment` that hold plaintext or records sealed with
+    // an older key, sealing every record with the current key. Like removal, only for segments
+    // a durable snapshot already covers: offsets change when a plaintext segment is sealed.
+    pub fn reseal_segments_before(&mut self, segment: u64) -> anyhow::Result<usize> {
+        let Some(keys) = &self.opts.keys else {
+            return Ok(0);
+        };
+        let mut resealed = 0;
+        for id in segment_ids(&self.dir)? {
+            if id >= segment || id >= self.segment {
+                break;
+            }
+            let mut entries = Vec::new();
+            let mut current = segment_format(&self.dir, id)? == Some(true);
+            let end = scan_segment(&self.dir, id, 0, Some(keys), |_, e, key_id| {
+                current &= key_id == Some(keys.current_id());
+                entries.push(e);
+            })?;
+            let path = segment_path(&self.dir, id);
+            if let ScanEnd::Torn { offset } = end {
+                return Err(WalError::Corrupt {
+                    path: path.display().to_string(),
+                    offset,
+                    reason: "truncated record in a sealed segment".to_string(),
+                }.into());
+            }
+            if current {
+                continue;
+            }
+
+            let tmp_path = path.with_extension(format!("{}.tmp", SEGMENT_EXT));
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(&header(true))?;
+            let mut offset = WAL_HEADER_LEN;
+            for entry in &entries {
+                let payload = keys.seal(&serde_json::to_vec(entry)?, &record_aad(id, offset));
+                offset += write_record(&mut writer, &payload)?;
+            }
+            writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+            resealed += 1;
+        }
+        if resealed > 0 {
+            fsync_dir(&self.dir)?;
+        }
+        Ok(resealed)
+    }
+
+    // Notes that segments covered by a checkpoint taken at `lamport_hw` were removed. The
+    // clock had observed every logged entry by then, so nothing newer can be missing.
+    pub fn mark_pruned(&mut self, lamport_hw: u64) {
+        self.pruned_hw = self.pruned_hw.max(lamport_hw);
+    }
+
+    pub fn pruned_hw(&self) -> u64 {
+        self.pruned_hw
+    }
+
+    // whether segments before the first one ever written are gone
+    pub fn is_trimmed(&self) -> anyhow::Result<bool> {
+        Ok(segment_ids(&self.dir)?.first().is_some_and(|&first| first > 1))
+    }
+
+    // The committed log as it stands, to read back without holding the WAL lock. Everything
+    // appended so far has been flushed by commit; records appended later aren't part of it.
+    pub fn committed(&self) -> anyhow::Result<CommittedLog> {
+        Ok(CommittedLog {
+            dir: self.dir.clone(),
+            keys: self.opts.keys.clone(),
+            segments: segment_ids(&self.dir)?.into_iter().filter(|&id| id <= self.segment).collect(),
+            end: self.position(),
+            max_ts: Arc::clone(&self.max_ts),
+        })
+    }
+
+    // position a snapshot can record so recovery replays only what comes after it
+    pub fn position(&self) -> WalPosition {
+        WalPosition { segment: self.segment, offset: self.offset }
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn keys(&self) -> Option<&Keyring> {
+        self.opts.keys.as_ref()
     }
 }
 
-pub fn replay_wal<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<LogEntry>> {
-    let path = path.as_ref();
-    if !path.exists() {
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXT))
+}
+
+// ids of the segment files in the directory, oldest first
+pub(crate) fn segment_ids(dir: &Path) -> anyhow::Result<Vec<u64>> {
+    if !dir.exists() {
         return Ok(Vec::new());
     }
 
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut entries = Vec::new();
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+// whether an existing segment is encrypted; None if it doesn't exist or has no header yet
+fn segment_format(dir: &Path, id: u64) -> anyhow::Result<Option<bool>> {
+    let path = segment_path(dir, id);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut file = File::open(&path)?;
+    if file.metadata()?.len() < WAL_HEADER_LEN {
+        return Ok(None);
+    }
+    Ok(Some(check_header(&mut file, &path)?))
+}
 
-    for (idx, line_res) in reader.lines().enumerate() {
-        let line = match line_res {
-            Ok(l) => l,
-            Err(e) => {
-                eprintln!("Error reading WAL at line {}: {}", idx + 1, e);
-                break; 
+// opens a segment for appending, writing the header if it is new; returns the end offset
+fn open_segment(dir: &Path, id: u64, sealed: bool) -> anyhow::Result<(File, u64)> {
+    let path = segment_path(dir, id);
+    let create_new = !path.exists();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(&path)?;
+
+    // If file was just created, fsync the directory so the entry itself is durable.
+    if create_new {
+        fsync_dir(dir)?;
+    }
+
+    let len = file.metadata()?.len();
+    if len < WAL_HEADER_LEN {
+        // new file, or a crash while writing the header: nothing can follow it yet
+        file.set_len(0)?;
+        file.write_all(&header(sealed))?;
+        file.sync_all()?;
+    } else {
+        check_header(&mut file, &path)?;
+    }
+
+    let offset = file.metadata()?.len();
+    Ok((file, offset))
+}
+
+fn header(sealed: bool) -> [u8; WAL_HEADER_LEN as usize] {
+    let version = if sealed { WAL_VERSION_SEALED } else { WAL_VERSION };
+    let mut buf = [0u8; WAL_HEADER_LEN as usize];
+    buf[..4].copy_from_slice(WAL_MAGIC);
+    buf[4..].copy_from_slice(&version.to_le_bytes());
+    buf
+}
+
+// returns whether the segment is encrypted
+fn check_header(file: &mut File, path: &Path) -> anyhow::Result<bool> {
+    let mut buf = [0u8; WAL_HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buf)?;
+    if buf == header(false) {
+        Ok(false)
+    } else if buf == header(true) {
+        Ok(true)
+    } else {
+        Err(WalError::BadHeader { path: path.display().to_string() }.into())
+    }
+}
+
+// returns the bytes written
+fn write_record(writer: &mut impl Write, payload: &[u8]) -> io::Result<u64> {
+    let crc = crc32fast::hash(payload);
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc.to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(RECORD_HEADER_LEN + payload.len() as u64)
+}
+
+// ties a sealed record to where it was written, so records can't be reordered or moved
+fn record_aad(segment: u64, offset: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[..8].copy_from_slice(&segment.to_le_bytes());
+    aad[8..].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+// how a segment scan ended
+enum ScanEnd {
+    Clean,
+    Torn { offset: u64 }, // an incomplete record starts here and runs to the end of the file
+}
+
+// whether some offset in `bytes` starts a whole record with a matching checksum
+fn intact_record_follows(bytes: &[u8]) -> bool {
+    let head = RECORD_HEADER_LEN as usize;
+    (0..bytes.len().saturating_sub(head)).any(|at| {
+        let rec_len = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[at + 4..at + head].try_into().unwrap());
+        // zero-filled space after a crash would otherwise pass as an empty record
+        rec_len > 0
+            && at + head + rec_len <= bytes.len()
+            && crc32fast::hash(&bytes[at + head..at + head + rec_len]) == crc
+    })
+}
+
+// Reads the records of one segment starting at `from`, handing each to `visit` along with
+// its offset and the id of the key it was sealed with. A bad record that ends exactly at EOF is reported as
+// torn rather than corrupt, since that is what a crash in the middle of a write leaves behind.
+// A record whose checksum holds but that fails to decrypt was written whole, so a wrong key
+// or tampering is an error wherever it is.
+fn scan_segment(
+    dir: &Path,
+    id: u64,
+    from: u64,
+    keys: Option<&Keyring>,
+    visit: impl FnMut(u64, LogEntry, Option<u32>),
+) -> anyhow::Result<ScanEnd> {
+    scan_range(dir, id, from, u64::MAX, keys, visit)
+}
+
+// like scan_segment, treating the segment as ending at `until` if it is longer
+fn scan_range(
+    dir: &Path,
+    id: u64,
+    from: u64,
+    until: u64,
+    keys: Option<&Keyring>,
+    mut visit: impl FnMut(u64, LogEntry, Option<u32>),
+) -> anyhow::Result<ScanEnd> {
+    let path = &segment_path(dir, id);
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len().min(until);
+    if len < WAL_HEADER_LEN {
+        return Ok(ScanEnd::Clean);
+    }
+    let sealed = check_header(&mut file, path)?;
+    if sealed && keys.is_none() {
+        return Err(WalError::Undecryptable {
+            path: path.display().to_string(),
+            offset: 0,
+            reason: "the segment is encrypted and no key is loaded".to_string(),
+        }.into());
+    }
+    if !sealed && keys.is_some_and(|k| !k.accepts_plaintext()) {
+        return Err(WalError::Undecryptable {
+            path: path.display().to_string(),
+            offset: 0,
+            reason: "the segment is not encrypted; pass --accept-plaintext if it predates encryption".to_string(),
+        }.into());
+    }
+
+    let from = from.max(WAL_HEADER_LEN);
+    file.seek(SeekFrom::Start(from))?;
+    let mut reader = BufReader::new(file);
+    let mut offset = from;
+
+    while offset < len {
+        let corrupt = |reason: String| WalError::Corrupt { path: path.display().to_string(), offset, reason };
+
+        if len - offset < RECORD_HEADER_LEN {
+            return Ok(ScanEnd::Torn { offset });
+        }
+        let mut head = [0u8; RECORD_HEADER_LEN as usize];
+        reader.read_exact(&mut head)?;
+        let rec_len = u32::from_le_bytes(head[..4].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(head[4..].try_into().unwrap());
+
+        // a length that was never written is corruption, even in the last record
+        if rec_len > MAX_RECORD_LEN {
+            return Err(corrupt(format!("record length {} exceeds the maximum", rec_len)).into());
+        }
+        let end = offset + RECORD_HEADER_LEN + rec_len;
+        // Nothing is logged as an empty record, so a zero length is space the crash left
+        // unwritten, e.g. zero-filled by the filesystem, like a record running past the end.
+        if end > len || rec_len == 0 {
+            // A partial write is the last thing in the file. If an intact record follows,
+            // the length was damaged and truncating here would throw that record away.
+            let mut rest = Vec::new();
+            reader.by_ref().take(len - offset - RECORD_HEADER_LEN).read_to_end(&mut rest)?;
+            if intact_record_follows(&rest) {
+                let reason = match rec_len {
+                    0 => "empty record".to_string(),
+                    _ => format!("record length {} runs past the end of the segment", rec_len),
+                };
+                return Err(corrupt(reason).into());
             }
+            return Ok(ScanEnd::Torn { offset });
+        }
+
+        let mut payload = vec![0u8; rec_len as usize];
+        reader.read_exact(&mut payload)?;
+        let parsed = if crc32fast::hash(&payload) != crc {
+            Err("checksum mismatch".to_string())
+        } else if let Some(keys) = keys.filter(|_| sealed) {
+            let json = keys.open(&payload, &record_aad(id, offset)).map_err(|e| WalError::Undecryptable {
+                path: path.display().to_string(),
+                offset,
+                reason: e.to_string(),
+            })?;
+            match serde_json::from_slice::<LogEntry>(&json) {
+                Ok(entry) => Ok(entry),
+                // authenticated, so not a partial write
+                Err(e) => return Err(corrupt(e.to_string()).into()),
+            }
+        } else {
+            serde_json::from_slice::<LogEntry>(&payload).map_err(|e| e.to_string())
         };
 
-        if line.trim().is_empty() {
+        match parsed {
+            Ok(entry) => visit(offset, entry, sealed.then(|| sealed_key_id(&payload)).flatten()),
+            // the final record may have been only partly flushed when we crashed
+            Err(_) if end == len => return Ok(ScanEnd::Torn { offset }),
+            Err(reason) => return Err(corrupt(reason).into()),
+        }
+        offset = end;
+    }
+
+    Ok(ScanEnd::Clean)
+}
+
+// replays the entries from position `from` onwards, e.g. the position recorded in a snapshot.
+// A torn record at the end of the newest segment is truncated away so the next append starts
+// on a clean boundary. Older segments were fsynced before rolling over, so a bad record in one
+// of them, or anywhere before the tail, is corruption.
+pub fn replay_wal<P: AsRef<Path>>(dir: P, from: WalPosition, keys: Option<&Keyring>) -> anyhow::Result<Vec<LogEntry>> {
+    replay(dir.as_ref(), from, keys, true)
+}
+
+// like replay_wal, but leaves the files alone: a torn tail is just where the entries end
+pub fn read_wal<P: AsRef<Path>>(dir: P, from: WalPosition, keys: Option<&Keyring>) -> anyhow::Result<Vec<LogEntry>> {
+    replay(dir.as_ref(), from, keys, false)
+}
+
+fn replay(dir: &Path, from: WalPosition, keys: Option<&Keyring>, repair: bool) -> anyhow::Result<Vec<LogEntry>> {
+    let ids = segment_ids(dir)?;
+    let mut from = from;
+    if let Some(&first) = ids.first() && from.segment < first {
+        if from != WalPosition::default() {
+            // segments the snapshot expects have been deleted by hand; replaying what is
+            // left is the best we can do and applying an entry twice is a no-op
+            eprintln!("WAL segment {} is missing, replaying from segment {}", from.segment, first);
+        }
+        from = WalPosition { segment: first, offset: 0 };
+    }
+
+    let mut entries = Vec::new();
+    for (idx, &id) in ids.iter().enumerate() {
+        if id < from.segment {
             continue;
         }
+        let path = segment_path(dir, id);
+        let start = if id == from.segment { from.offset } else { 0 };
+        let is_last = idx == ids.len() - 1;
 
-        match serde_json::from_str::<LogEntry>(&line) {
-            Ok(entry) => entries.push(entry),
-            Err(e) => {
-                eprintln!("Error parsing WAL entry at line {}: {}. Stopping replay.", idx + 1, e);
-                break; 
+        if let ScanEnd::Torn { offset } = scan_segment(dir, id, start, keys, |_, e, _| entries.push(e))? {
+            if !is_last {
+                return Err(WalError::Corrupt {
+                    path: path.display().to_string(),
+                    offset,
+                    reason: "truncated record in a sealed segment".to_string(),
+                }.into());
+            }
+            if repair {
+                truncate_torn_tail(&path, offset)?;
             }
         }
     }
@@ -90,8 +707,141 @@ pub fn replay_wal<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<LogEntry>> {
     Ok(entries)
 }
 
+// summarises every segment; a record still being written at the tail is simply skipped
+pub fn list_segments<P: AsRef<Path>>(dir: P, keys: Option<&Keyring>) -> anyhow::Result<Vec<SegmentInfo>> {
+    let dir = dir.as_ref();
+    let mut infos = Vec::new();
+    for id in segment_ids(dir)? {
+        let path = segment_path(dir, id);
+        let mut info = SegmentInfo {
+            segment: id,
+            bytes: fs::metadata(&path)?.len(),
+            entries: 0,
+            encrypted: segment_format(dir, id)? == Some(true),
+            first_ts: None,
+            last_ts: None,
+        };
+        scan_segment(dir, id, 0, keys, |_, e, _| {
+            info.entries += 1;
+            info.first_ts.get_or_insert(e.ts);
+            info.last_ts = Some(e.ts);
+        })?;
+        infos.push(info);
+    }
+    Ok(infos)
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentCheck {
+    pub segment: u64,
+    pub entries: u64, // readable records, up to the first bad one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub torn_at: Option<u64>, // a partly written record at the tail of the newest segment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // corruption, or a record that can't be decrypted
+}
+
+// For offline tools: reads every segment up to its end or its first bad record, handing each
+// readable record to `visit` with its segment and offset, and reports what stopped each one.
+// Unlike replay, a bad segment doesn't end the walk, and nothing is modified.
+pub fn check_wal<P: AsRef<Path>>(
+    dir: P,
+    keys: Option<&Keyring>,
+    mut visit: impl FnMut(u64, u64, LogEntry),
+) -> anyhow::Result<Vec<SegmentCheck>> {
+    let dir = dir.as_ref();
+    let ids = segment_ids(dir)?;
+    let mut checks = Vec::new();
+    for (idx, &id) in ids.iter().enumerate() {
+        let mut check = SegmentCheck { segment: id, entries: 0, torn_at: None, error: None };
+        let scanned = scan_segment(dir, id, 0, keys, |offset, e, _| {
+            check.entries += 1;
+            visit(id, offset, e);
+        });
+        match scanned {
+            Ok(ScanEnd::Clean) => {}
+            Ok(ScanEnd::Torn { offset }) if idx == ids.len() - 1 => check.torn_at = Some(offset),
+            Ok(ScanEnd::Torn { offset }) => {
+                check.error = Some(format!("truncated record at offset {} in a sealed segment", offset));
+            }
+            Err(e) => check.error = Some(e.to_string()),
+        }
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+// Truncates a torn record off the end of the newest segment, as replay would at startup.
+// Returns where the segment now ends, if there was anything to cut.
+pub fn repair_torn_tail<P: AsRef<Path>>(dir: P, keys: Option<&Keyring>) -> anyhow::Result<Option<WalPosition>> {
+    let dir = dir.as_ref();
+    let Some(&last) = segment_ids(dir)?.last() else {
+        return Ok(None);
+    };
+    match scan_segment(dir, last, 0, keys, |_, _, _| {})? {
+        ScanEnd::Clean => Ok(None),
+        ScanEnd::Torn { offset } => {
+            truncate_torn_tail(&segment_path(dir, last), offset)?;
+            Ok(Some(WalPosition { segment: last, offset }))
+        }
+    }
+}
+
+fn truncate_torn_tail(path: &Path, offset: u64) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    eprintln!(
+        "WAL segment {} has a torn record at offset {}, truncating {} trailing bytes",
+        path.display(), offset, len - offset
+    );
+    file.set_len(offset)?;
+    file.sync_all()
+}
+
+// Earlier versions logged to a single file (WAL_PATH, default wal.log) holding one JSON entry
+// per line. Copies its entries into segment 1 of an empty WAL directory, in order, then renames
+// the file so it is never applied twice. A partly written last line is dropped, as the old
+// replay did; a bad line before it is corruption.
+pub fn migrate_legacy_wal<P: AsRef<Path>, Q: AsRef<Path>>(path: P, dir: Q, keys: Option<&Keyring>) -> anyhow::Result<u64> {
+    let (path, dir) = (path.as_ref(), dir.as_ref());
+    if fs::metadata(dir).is_ok() && !segment_ids(dir)?.is_empty() {
+        anyhow::bail!(
+            "both a single-file WAL ({}) and WAL segments in {} exist; move one of them away",
+            path.display(), dir.display()
+        );
+    }
+
+    let text = fs::read_to_string(path)?;
+    let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+    let mut entries = Vec::with_capacity(lines.len());
+    for (idx, line) in lines.iter().enumerate() {
+        match serde_json::from_str::<LogEntry>(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if idx == lines.len() - 1 && !text.ends_with('\n') => {
+                eprintln!("Dropping the torn last line of {}", path.display());
+            }
+            Err(e) => anyhow::bail!("{} is corrupt at line {}: {}", path.display(), idx + 1, e),
+        }
+    }
+
+    let opts = WalOptions { segment_bytes: u64::MAX, durability: Durability::Always, fsync_seconds: None, keys: keys.cloned() };
+    let mut wal = Wal::open(dir, opts, 1)?;
+    for entry in &entries {
+        wal.append(entry)?;
+    }
+    wal.sync()?;
+
+    let mut done = path.as_os_str().to_owned();
+    done.push(".migrated");
+    fs::rename(path, &done)?;
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fsync_dir(parent)?;
+    }
+    Ok(entries.len() as u64)
+}
+
 // Make files durable
-fn fsync_dir(dir: &Path) -> io::Result<()> {
+pub(crate) fn fsync_dir(dir: &Path) -> io::Result<()> {
     #[cfg(target_family = "unix")]
     {
         use std::os::unix::fs::OpenOptionsExt;
@@ -102,4 +852,292 @@ fn fsync_dir(dir: &Path) -> io::Result<()> {
     {
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Operation;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kv-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn opts() -> WalOptions {
+        WalOptions { segment_bytes: 1 << 20, durability: Durability::Os, fsync_seconds: None, keys: None }
+    }
+
+    fn put(ts: u64, key: &str) -> LogEntry {
+        let operation = Operation::Put { key: key.to_string(), value: b"v".to_vec(), content_type: None, expires_at: None, context: None };
+        LogEntry { ts, node_id: 1, operation }
+    }
+
+    fn write_entries(dir: &Path, n: u64) -> WalPosition {
+        let mut wal = Wal::open(dir, opts(), 1).unwrap();
+        for ts in 1..=n {
+            wal.append(&put(ts, &format!("k{}", ts))).unwrap();
+        }
+        wal.commit().unwrap();
+        wal.position()
+    }
+
+    fn ts_of(entries: &[LogEntry]) -> Vec<u64> {
+        entries.iter().map(|e| e.ts).collect()
+    }
+
+    #[test]
+    fn commits_fsync_only_under_the_always_policy() {
+        let fsyncs = |durability: Durability| {
+            let dir = temp_dir(&format!("durability-{}", durability.label()));
+            let hist = Histogram::with_opts(prometheus::HistogramOpts::new("fsync", "fsync")).unwrap();
+            let mut wal = Wal::open(&dir, WalOptions { durability, fsync_seconds: Some(hist.clone()), ..opts() }, 1).unwrap();
+            wal.append(&put(1, "a")).unwrap();
+            wal.commit().unwrap();
+            let after_commit = hist.get_sample_count();
+            // what the interval policy's timer does
+            wal.sync_if_dirty().unwrap();
+            wal.sync_if_dirty().unwrap();
+            let after_timer = hist.get_sample_count();
+            drop(wal);
+            // committed records are readable whatever the policy
+            assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), None).unwrap()), vec![1]);
+            fs::remove_dir_all(&dir).unwrap();
+            (after_commit, after_timer)
+        };
+
+        assert_eq!(fsyncs(Durability::Always), (1, 1));
+        assert_eq!(fsyncs(Durability::Interval(Duration::from_millis(5))), (0, 1));
+        assert_eq!(fsyncs(Durability::Os), (0, 1));
+    }
+
+    #[test]
+    fn read_only_wal_creates_and_writes_nothing() {
+        let dir = temp_dir("read-only");
+        let mut wal = Wal::open_read_only(&dir, opts()).unwrap();
+        assert!(wal.append(&put(1, "a")).is_err());
+        assert!(!dir.exists());
+
+        let end = write_entries(&dir, 2);
+        let mut wal = Wal::open_read_only(&dir, opts()).unwrap();
+        assert_eq!(wal.position(), end);
+        assert!(wal.append(&put(3, "a")).is_err());
+        assert!(wal.commit().is_err());
+        assert_eq!(fs::metadata(segment_path(&dir, 1)).unwrap().len(), end.offset);
+        assert_eq!(segment_ids(&dir).unwrap(), vec![1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_from_a_position() {
+        let dir = temp_dir("position");
+        write_entries(&dir, 2);
+        let mid = Wal::open(&dir, opts(), 1).unwrap().position();
+        write_entries(&dir, 3);
+
+        assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), None).unwrap()), vec![1, 2, 1, 2, 3]);
+        assert_eq!(ts_of(&replay_wal(&dir, mid, None).unwrap()), vec![1, 2, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_torn_tail() {
+        let dir = temp_dir("torn");
+        let end = write_entries(&dir, 3);
+        let path = segment_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        // the header and half the payload of a fourth record
+        file.write_all(&40u32.to_le_bytes()).unwrap();
+        file.write_all(&[0; 24]).unwrap();
+        drop(file);
+
+        assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), None).unwrap()), vec![1, 2, 3]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), end.offset);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_zero_filled_tail() {
+        let dir = temp_dir("zeroed");
+        let end = write_entries(&dir, 3);
+        let path = segment_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        drop(file);
+
+        assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), None).unwrap()), vec![1, 2, 3]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), end.offset);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zeroed_record_before_intact_records_is_corruption() {
+        let dir = temp_dir("zeroed-middle");
+        write_entries(&dir, 3);
+        let path = segment_path(&dir, 1);
+        let mut bytes = fs::read(&path).unwrap();
+        let at = WAL_HEADER_LEN as usize;
+        let first_len = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        bytes[at..at + RECORD_HEADER_LEN as usize + first_len].fill(0);
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(replay_wal(&dir, WalPosition::default(), None).is_err());
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_append_a_record_replay_would_reject() {
+        let dir = temp_dir("too-big");
+        let mut wal = Wal::open(&dir, opts(), 1).unwrap();
+        let operation = Operation::Put { key: "big".to_string(), value: vec![0; MAX_RECORD_LEN as usize], content_type: None, expires_at: None, context: None };
+        assert!(wal.append(&LogEntry { ts: 1, node_id: 1, operation }).is_err());
+        wal.append(&put(2, "small")).unwrap();
+        wal.commit().unwrap();
+        drop(wal);
+
+        assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), None).unwrap()), vec![2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_length_is_corruption_even_at_the_tail() {
+        let dir = temp_dir("oversized");
+        write_entries(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(segment_path(&dir, 1)).unwrap();
+        file.write_all(&(MAX_RECORD_LEN as u32 + 1).to_le_bytes()).unwrap();
+        file.write_all(&[0; 4]).unwrap();
+        drop(file);
+
+        assert!(replay_wal(&dir, WalPosition::default(), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_length_before_intact_records_is_corruption() {
+        let dir = temp_dir("damaged");
+        write_entries(&dir, 3);
+        let path = segment_path(&dir, 1);
+        let len = fs::metadata(&path).unwrap().len();
+        // stretch the first record over the rest of the file
+        let mut bytes = fs::read(&path).unwrap();
+        let at = WAL_HEADER_LEN as usize;
+        bytes[at..at + 4].copy_from_slice(&(len as u32).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(replay_wal(&dir, WalPosition::default(), None).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rollback_undoes_appends_across_a_roll() {
+        let dir = temp_dir("rollback");
+        let small = WalOptions { segment_bytes: 200, ..opts() };
+        let mut wal = Wal::open(&dir, small, 1).unwrap();
+        for ts in 1..=2 {
+            wal.append(&put(ts, "a")).unwrap();
+        }
+        wal.commit().unwrap();
+
+        let mark = wal.mark();
+        for ts in 3..=10 {
+            wal.append(&put(ts, "b")).unwrap();
+        }
+        assert!(wal.position().segment > mark.segment);
+        wal.rollback(mark).unwrap();
+        wal.append(&put(11, "c")).unwrap();
+        wal.commit().unwrap();
+        drop(wal);
+
+        assert_eq!(segment_ids(&dir).unwrap(), vec![1]);
+        assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), None).unwrap()), vec![1, 2, 11]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn committed_log_ends_where_it_was_taken() {
+        let dir = temp_dir("committed");
+        let small = WalOptions { segment_bytes: 200, ..opts() };
+        let mut wal = Wal::open(&dir, small, 1).unwrap();
+        for ts in 1..=6 {
+            wal.append(&put(ts, "a")).unwrap();
+        }
+        wal.commit().unwrap();
+        let log = wal.committed().unwrap();
+        wal.append(&put(7, "a")).unwrap();
+        wal.commit().unwrap();
+
+        let read = |since| {
+            let mut seen = Vec::new();
+            log.read_since(since, |e| {
+                seen.push(e.ts);
+                true
+            }).unwrap();
+            seen
+        };
+        assert_eq!(read(0), vec![1, 2, 3, 4, 5, 6]);
+        // again, now that the sealed segments' highest ts are known
+        assert_eq!(read(4), vec![5, 6]);
+        assert_eq!(read(4), vec![5, 6]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_segments_need_the_key_and_plaintext_needs_consent() {
+        let dir = temp_dir("sealed");
+        write_entries(&dir, 2);
+        let key_path = dir.join("key");
+        fs::write(&key_path, "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=").unwrap();
+        let keys = Keyring::load(&key_path, &[]).unwrap();
+
+        // the plaintext segment is refused unless that's asked for
+        assert!(Wal::open(&dir, WalOptions { keys: Some(keys.clone()), ..opts() }, 1).is_ok());
+        assert!(replay_wal(&dir, WalPosition::default(), Some(&keys)).is_err());
+        let migrating = keys.clone().accepting_plaintext(true);
+        let mut wal = Wal::open(&dir, WalOptions { keys: Some(migrating.clone()), ..opts() }, 1).unwrap();
+        wal.append(&put(3, "c")).unwrap();
+        wal.commit().unwrap();
+        assert_eq!(wal.position().segment, 2);
+        drop(wal);
+
+        assert_eq!(ts_of(&replay_wal(&dir, WalPosition::default(), Some(&migrating)).unwrap()), vec![1, 2, 3]);
+        let sealed = WalPosition { segment: 2, offset: 0 };
+        assert_eq!(ts_of(&replay_wal(&dir, sealed, Some(&keys)).unwrap()), vec![3]);
+        assert!(replay_wal(&dir, sealed, None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_a_single_file_wal() {
+        let dir = temp_dir("legacy");
+        fs::create_dir_all(&dir).unwrap();
+        let legacy = dir.join("wal.log");
+        let lines = [
+            r#"{"ts":1,"node_id":1,"operation":{"Put":{"key":"a","value":"x"}}}"#,
+            r#"{"ts":2,"node_id":1,"operation":{"Delete":{"key":"a"}}}"#,
+            r#"{"ts":3,"node_id":1,"operation":{"Pu"#,
+        ];
+        fs::write(&legacy, lines.join("\n")).unwrap();
+
+        assert_eq!(migrate_legacy_wal(&legacy, dir.join("wal"), None).unwrap(), 2);
+        assert_eq!(ts_of(&replay_wal(dir.join("wal"), WalPosition::default(), None).unwrap()), vec![1, 2]);
+        assert!(!legacy.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_checksum_before_the_tail_is_corruption() {
+        let dir = temp_dir("checksum");
+        write_entries(&dir, 3);
+        let path = segment_path(&dir, 1);
+        let mut bytes = fs::read(&path).unwrap();
+        let at = WAL_HEADER_LEN as usize + RECORD_HEADER_LEN as usize;
+        bytes[at] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(replay_wal(&dir, WalPosition::default(), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
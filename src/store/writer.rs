@@ -0,0 +1,458 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::{broadcast, mpsc, oneshot, Mutex}, time::MissedTickBehavior};
+
+use crate::store::{Store, Value, Wal, Durability, LamportClock};
+use crate::util::{LogEntry, Operation, now_ms};
+
+const QUEUE_DEPTH: usize = 4096;
+const GROUP_MAX: usize = 512;
+const EVENTS_DEPTH: usize = 1024;
+
+struct WriteReq {
+    entries: Vec<LogEntry>,
+    done: oneshot::Sender<Result<Vec<Option<Value>>, String>>,
+}
+
+struct CondReq {
+    entry: LogEntry,
+    checks: Vec<(String, Condition)>,
+    done: oneshot::Sender<Result<CondOutcome, String>>,
+}
+
+// keys whose check failed, with their current value
+type Conflicts = Vec<(String, Option<Value>)>;
+
+struct CheckReq {
+    checks: Vec<(String, Condition)>,
+    done: oneshot::Sender<Result<Conflicts, String>>,
+}
+
+struct AtomicReq {
+    key: String,
+    op: AtomicOp,
+    node_id: u64,
+    expires_at: Option<u64>,
+    max_len: usize,
+    done: oneshot::Sender<Result<AtomicOutcome, String>>,
+}
+
+enum Request {
+    Write(WriteReq),
+    Cond(CondReq),
+    Check(CheckReq),
+    Atomic(AtomicReq),
+}
+
+// checks a conditional write makes against the key's current value
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Exists,                      // If-Match: *
+    Absent,                      // If-None-Match: *
+    VersionIn(Vec<(u64, u64)>),  // If-Match: (ts, node_id) versions
+    VersionNotIn(Vec<(u64, u64)>), // If-None-Match: (ts, node_id) versions
+    Unchanged(u64, u64),         // (ts, node_id) is still a current version, expired or not
+}
+
+impl Condition {
+    // tombstones and expired values count as absent, except to Unchanged
+    pub fn holds(&self, current: Option<&Value>, now_ms: u64) -> bool {
+        let live = current.filter(|v| v.is_live(now_ms));
+        let version = live.map(|v| (v.ts, v.node_id));
+        match self {
+            Condition::Exists => live.is_some(),
+            Condition::Absent => live.is_none(),
+            Condition::VersionIn(versions) => version.is_some_and(|v| versions.contains(&v)),
+            Condition::VersionNotIn(versions) => !version.is_some_and(|v| versions.contains(&v)),
+            Condition::Unchanged(ts, node_id) => current.is_some_and(|v| {
+                std::iter::once(v).chain(&v.siblings).any(|s| s.data.is_some() && (s.ts, s.node_id) == (*ts, *node_id))
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CondOutcome {
+    // the entry as written, with the timestamp it was given, and the value it replaced
+    Applied { entry: LogEntry, replaced: Option<Box<Value>> },
+    // the keys whose check failed, with their current value
+    Failed { conflicts: Vec<(String, Option<Value>)> },
+}
+
+// a read-modify-write of one key's value, evaluated by the writer
+#[derive(Debug, Clone)]
+pub enum AtomicOp {
+    Incr(i64),          // the value read as a decimal integer; a missing key counts as 0
+    Append(Vec<u8>),    // a missing key counts as empty
+    GetSet(Vec<u8>),    // replaces the value, handing back the previous one
+}
+
+impl AtomicOp {
+    // the value the op writes over `current`; Err says why it can't
+    fn apply(&self, current: Option<&[u8]>) -> Result<Vec<u8>, String> {
+        match self {
+            AtomicOp::Incr(by) => {
+                let n: i64 = match current {
+                    Some(bytes) => std::str::from_utf8(bytes)
+                        .ok()
+                        .and_then(|s| s.trim().parse().ok())
+                        .ok_or("value is not an integer")?,
+                    None => 0,
+                };
+                let n = n.checked_add(*by).ok_or("increment would overflow")?;
+                Ok(n.to_string().into_bytes())
+            }
+            AtomicOp::Append(suffix) => Ok([current.unwrap_or_default(), suffix].concat()),
+            AtomicOp::GetSet(value) => Ok(value.clone()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AtomicOutcome {
+    // the put as written, and the live value it replaced
+    Applied { entry: LogEntry, previous: Option<Box<Value>> },
+    // the op doesn't apply to the current value, e.g. incrementing text
+    Rejected(String),
+    // the value it would write is this long, over the limit it was given
+    TooLarge(usize),
+}
+
+// handle to the group-commit task; cheap to clone into every handler
+#[derive(Clone, Debug)]
+pub struct WalWriter {
+    tx: mpsc::Sender<Request>,
+    applied: broadcast::Sender<LogEntry>,
+}
+
+impl WalWriter {
+    // resolves once the entries are durable in the WAL and applied to the store;
+    // returns, per entry, the value it replaced (see Store::apply)
+    pub async fn submit(&self, entries: Vec<LogEntry>) -> anyhow::Result<Vec<Option<Value>>> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(Request::Write(WriteReq { entries, done }))
+            .await
+            .map_err(|_| anyhow::anyhow!("WAL writer has stopped"))?;
+        wait.await?.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    // Writes `entry` only if every (key, condition) check holds for the key's current value.
+    // The checks, the timestamp (replacing entry.ts) and the write happen in the writer task
+    // with nothing applied in between, so conditional writes are linearizable on the node
+    // that makes them.
+    pub async fn submit_if(&self, entry: LogEntry, checks: Vec<(String, Condition)>) -> anyhow::Result<CondOutcome> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(Request::Cond(CondReq { entry, checks, done }))
+            .await
+            .map_err(|_| anyhow::anyhow!("WAL writer has stopped"))?;
+        wait.await?.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    // Evaluates the checks like submit_if, all at one point in the apply order, but writes
+    // nothing. Returns the keys whose check failed, with their current value.
+    pub async fn check(&self, checks: Vec<(String, Condition)>) -> anyhow::Result<Conflicts> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(Request::Check(CheckReq { checks, done }))
+            .await
+            .map_err(|_| anyhow::anyhow!("WAL writer has stopped"))?;
+        wait.await?.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    // Runs `op` on the key's current live value and writes the result as a plain put stamped
+    // by the writer, so nothing else touches the key in between, unless it is longer than
+    // `max_len`. Incr and append keep the value's content type and TTL unless `expires_at`
+    // sets a new one; get-and-set replaces both, like a put.
+    pub async fn submit_atomic(&self, key: String, op: AtomicOp, node_id: u64, expires_at: Option<u64>, max_len: usize) -> anyhow::Result<AtomicOutcome> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(Request::Atomic(AtomicReq { key, op, node_id, expires_at, max_len, done }))
+            .await
+            .map_err(|_| anyhow::anyhow!("WAL writer has stopped"))?;
+        wait.await?.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    // Every entry the writer applies from now on, in apply order, whether it lost under LWW
+    // or not. Entries are published under the WAL lock, so subscribing while holding it splits
+    // the log cleanly between what is already on disk and what the receiver will get.
+    pub fn subscribe(&self) -> broadcast::Receiver<LogEntry> {
+        self.applied.subscribe()
+    }
+}
+
+// Starts the single task that owns appends to the WAL. Whatever requests queue up while one
+// fsync is in flight are appended together and share the next fsync, so concurrent writers
+// pay for one sync per batch instead of one each. Nobody is answered before their entries
+// are committed under the WAL's durability policy, and entries are applied to the store under
+// the WAL lock so snapshots stay consistent with the WAL position. Conditional writes and
+// atomic ops first commit everything queued ahead of them, so they see its effect.
+pub fn spawn_wal_writer(
+    wal: Arc<Mutex<Wal>>,
+    store: Arc<Store>,
+    clock: Arc<LamportClock>,
+    before_sync_ms: u64,
+) -> WalWriter {
+    let (tx, mut rx) = mpsc::channel::<Request>(QUEUE_DEPTH);
+    let (applied, _) = broadcast::channel::<LogEntry>(EVENTS_DEPTH);
+    let publish = applied.clone();
+
+    tokio::spawn(async move {
+        let sync_every = match wal.lock().await.durability() {
+            Durability::Interval(every) => Some(every),
+            Durability::Always | Durability::Os => None,
+        };
+        let mut ticker = tokio::time::interval(sync_every.unwrap_or(Duration::from_secs(3600)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let first = tokio::select! {
+                maybe = rx.recv() => match maybe {
+                    Some(req) => req,
+                    None => break,
+                },
+                _ = ticker.tick(), if sync_every.is_some() => {
+                    if let Err(e) = wal.lock().await.sync_if_dirty() {
+                        eprintln!("Background WAL fsync failed: {}", e);
+                    }
+                    continue;
+                }
+            };
+
+            let mut queued = vec![first];
+            while queued.len() < GROUP_MAX {
+                match rx.try_recv() {
+                    Ok(req) => queued.push(req),
+                    Err(_) => break,
+                }
+            }
+
+            let mut wal = wal.lock().await;
+            let mut batch = Vec::new();
+            for req in queued {
+                match req {
+                    Request::Write(req) => batch.push(req),
+                    Request::Cond(req) => {
+                        commit_batch(&mut wal, &store, &publish, std::mem::take(&mut batch), before_sync_ms).await;
+                        let outcome = commit_conditional(&mut wal, &store, &clock, &publish, req.entry, &req.checks, before_sync_ms).await;
+                        let _ = req.done.send(outcome.map_err(|e| e.to_string()));
+                    }
+                    Request::Check(req) => {
+                        commit_batch(&mut wal, &store, &publish, std::mem::take(&mut batch), before_sync_ms).await;
+                        let conflicts = failed_checks(&store, &req.checks);
+                        let _ = req.done.send(conflicts.map_err(|e| e.to_string()));
+                    }
+                    Request::Atomic(req) => {
+                        commit_batch(&mut wal, &store, &publish, std::mem::take(&mut batch), before_sync_ms).await;
+                        let outcome = commit_atomic(&mut wal, &store, &clock, &publish, &req, before_sync_ms).await;
+                        let _ = req.done.send(outcome.map_err(|e| e.to_string()));
+                    }
+                }
+            }
+            commit_batch(&mut wal, &store, &publish, batch, before_sync_ms).await;
+        }
+    });
+
+    WalWriter { tx, applied }
+}
+
+// appends and commits the batch, then applies it and answers each request
+async fn commit_batch(
+    wal: &mut Wal,
+    store: &Store,
+    publish: &broadcast::Sender<LogEntry>,
+    batch: Vec<WriteReq>,
+    before_sync_ms: u64,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let entries = batch.iter().flat_map(|req| req.entries.iter());
+    match write_entries(wal, entries, before_sync_ms).await {
+        Ok(()) => {
+            for req in batch {
+                let applied: anyhow::Result<Vec<_>> = req.entries
+                    .into_iter()
+                    .map(|entry| {
+                        let replaced = store.apply(entry.clone())?;
+                        // no receivers is not an error
+                        let _ = publish.send(entry);
+                        Ok(replaced)
+                    })
+                    .collect();
+                let _ = req.done.send(applied.map_err(|e| e.to_string()));
+            }
+        }
+        Err(e) => {
+            eprintln!("WAL write failed: {}", e);
+            for req in batch {
+                let _ = req.done.send(Err(e.to_string()));
+            }
+        }
+    }
+}
+
+async fn commit_conditional(
+    wal: &mut Wal,
+    store: &Store,
+    clock: &LamportClock,
+    publish: &broadcast::Sender<LogEntry>,
+    mut entry: LogEntry,
+    checks: &[(String, Condition)],
+    before_sync_ms: u64,
+) -> anyhow::Result<CondOutcome> {
+    let now = now_ms();
+    let mut conflicts = Vec::new();
+    for (key, condition) in checks {
+        let current = store.get(key)?;
+        if !condition.holds(current.as_ref(), now) {
+            conflicts.push((key.clone(), current));
+        } else if let Some(cur) = &current {
+            // stamp the write after every value it was checked against, so it also wins under LWW
+            clock.tick_observe(cur.ts);
+        }
+    }
+    if !conflicts.is_empty() {
+        return Ok(CondOutcome::Failed { conflicts });
+    }
+    entry.ts = clock.tick_send();
+
+    write_entries(wal, std::iter::once(&entry), before_sync_ms).await?;
+    let replaced = store.apply(entry.clone())?.map(Box::new);
+    let _ = publish.send(entry.clone());
+    Ok(CondOutcome::Applied { entry, replaced })
+}
+
+fn failed_checks(store: &Store, checks: &[(String, Condition)]) -> anyhow::Result<Conflicts> {
+    let now = now_ms();
+    let mut conflicts = Vec::new();
+    for (key, condition) in checks {
+        let current = store.get(key)?;
+        if !condition.holds(current.as_ref(), now) {
+            conflicts.push((key.clone(), current));
+        }
+    }
+    Ok(conflicts)
+}
+
+async fn commit_atomic(
+    wal: &mut Wal,
+    store: &Store,
+    clock: &LamportClock,
+    publish: &broadcast::Sender<LogEntry>,
+    req: &AtomicReq,
+    before_sync_ms: u64,
+) -> anyhow::Result<AtomicOutcome> {
+    let current = store.get(&req.key)?;
+    if current.as_ref().is_some_and(|v| v.crdt.is_some()) {
+        return Ok(AtomicOutcome::Rejected("key holds a counter or set".to_string()));
+    }
+    let live = current.as_ref().filter(|v| v.is_live(now_ms()));
+    let value = match req.op.apply(live.and_then(|v| v.data.as_deref())) {
+        Ok(value) => value,
+        Err(reason) => return Ok(AtomicOutcome::Rejected(reason)),
+    };
+    if value.len() > req.max_len {
+        return Ok(AtomicOutcome::TooLarge(value.len()));
+    }
+
+    let kept = live.filter(|_| !matches!(req.op, AtomicOp::GetSet(_)));
+    let operation = Operation::Put {
+        key: req.key.clone(),
+        value,
+        content_type: kept.and_then(|v| v.content_type.clone()),
+        expires_at: req.expires_at.or(kept.and_then(|v| v.expires_at)),
+        // with siblings the result replaces all of them
+        context: store.keeps_siblings().then(|| current.as_ref().map(Value::causal_context).unwrap_or_default()),
+    };
+    if let Some(cur) = &current {
+        // stamp the write after the value it was computed from, so it also wins under LWW
+        clock.tick_observe(cur.ts);
+    }
+    let entry = LogEntry { ts: clock.tick_send(), node_id: req.node_id, operation };
+
+    write_entries(wal, std::iter::once(&entry), before_sync_ms).await?;
+    store.apply(entry.clone())?;
+    let _ = publish.send(entry.clone());
+    Ok(AtomicOutcome::Applied { entry, previous: live.cloned().map(Box::new) })
+}
+
+// Appends and commits the entries. If any of that fails they are rolled back, so a write
+// whose caller got an error can't reappear on replay or be followed by later records.
+async fn write_entries<'a>(
+    wal: &mut Wal,
+    entries: impl Iterator<Item = &'a LogEntry>,
+    before_sync_ms: u64,
+) -> anyhow::Result<()> {
+    let mark = wal.mark();
+    let written = append_and_commit(wal, entries, before_sync_ms).await;
+    if written.is_err() && let Err(e) = wal.rollback(mark) {
+        eprintln!("WAL rollback failed, refusing further writes: {}", e);
+    }
+    written
+}
+
+async fn append_and_commit<'a>(
+    wal: &mut Wal,
+    entries: impl Iterator<Item = &'a LogEntry>,
+    before_sync_ms: u64,
+) -> anyhow::Result<()> {
+    for entry in entries {
+        wal.append(entry)?;
+    }
+
+    // tests chaos injection
+    if before_sync_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(before_sync_ms)).await;
+    }
+    wal.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::WalOptions;
+
+    #[test]
+    fn incr_counts_from_zero_and_refuses_what_isnt_an_integer() {
+        assert_eq!(AtomicOp::Incr(5).apply(None).unwrap(), b"5");
+        assert_eq!(AtomicOp::Incr(-3).apply(Some(b" 2\n")).unwrap(), b"-1");
+        assert!(AtomicOp::Incr(1).apply(Some(b"two")).is_err());
+        assert!(AtomicOp::Incr(1).apply(Some(i64::MAX.to_string().as_bytes())).is_err());
+    }
+
+    #[test]
+    fn append_and_getset_build_on_the_current_value() {
+        assert_eq!(AtomicOp::Append(b"b".to_vec()).apply(None).unwrap(), b"b");
+        assert_eq!(AtomicOp::Append(b"b".to_vec()).apply(Some(b"a")).unwrap(), b"ab");
+        assert_eq!(AtomicOp::GetSet(b"new".to_vec()).apply(Some(b"old")).unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn atomic_ops_hand_back_the_previous_value_and_stop_at_the_limit() {
+        let dir = std::env::temp_dir().join(format!("kv-writer-atomic-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let opts = WalOptions { segment_bytes: 1 << 20, durability: Durability::Os, fsync_seconds: None, keys: None };
+        let wal = Arc::new(Mutex::new(Wal::open(&dir, opts, 1).unwrap()));
+        let store = Arc::new(Store::new());
+        let writer = spawn_wal_writer(wal, Arc::clone(&store), Arc::new(LamportClock::new()), 0);
+        let run = |op: AtomicOp| writer.submit_atomic("k".to_string(), op, 1, None, 4);
+
+        let AtomicOutcome::Applied { previous, .. } = run(AtomicOp::Append(b"ab".to_vec())).await.unwrap() else {
+            panic!("append to a missing key applies");
+        };
+        assert!(previous.is_none());
+        assert!(matches!(run(AtomicOp::Append(b"cde".to_vec())).await.unwrap(), AtomicOutcome::TooLarge(5)));
+        assert!(matches!(run(AtomicOp::Incr(1)).await.unwrap(), AtomicOutcome::Rejected(_)));
+
+        let AtomicOutcome::Applied { entry, previous } = run(AtomicOp::GetSet(b"7".to_vec())).await.unwrap() else {
+            panic!("getset applies");
+        };
+        assert_eq!(previous.and_then(|v| v.data), Some(b"ab".to_vec()));
+        assert_eq!(store.get("k").unwrap().map(|v| v.ts), Some(entry.ts));
+        assert!(matches!(run(AtomicOp::Incr(1)).await.unwrap(), AtomicOutcome::Applied { .. }));
+        assert_eq!(store.get("k").unwrap().and_then(|v| v.data), Some(b"8".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
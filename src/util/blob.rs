@@ -0,0 +1,46 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Byte values inside our JSON formats (WAL records, snapshots, replication, sled) are written
+// as {"b64": "..."}. A plain string is read as UTF-8 text, which is how values were stored
+// before they were binary, so old logs and snapshots still load.
+#[derive(Serialize)]
+struct Encoded {
+    b64: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Text(String),
+    Encoded { b64: String },
+}
+
+impl Repr {
+    fn into_bytes<E: serde::de::Error>(self) -> Result<Vec<u8>, E> {
+        match self {
+            Repr::Text(text) => Ok(text.into_bytes()),
+            Repr::Encoded { b64 } => STANDARD.decode(b64).map_err(E::custom),
+        }
+    }
+}
+
+pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    Encoded { b64: STANDARD.encode(bytes) }.serialize(s)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    Repr::deserialize(d)?.into_bytes()
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        bytes.as_ref().map(|b| Encoded { b64: STANDARD.encode(b) }).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<Repr>::deserialize(d)?.map(Repr::into_bytes).transpose()
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+
+// Conflict-free values. Operations carry a piece of state rather than an instruction, and
+// merging is a join (per-node max, set union), so replicas that received the same operations
+// end up equal whatever order and however many times they were applied.
+
+// Counter that goes up and down: each node keeps its own running totals of what it added and
+// subtracted, and only ever raises them. Deleting the counter records the totals it had seen
+// as the point each node counts from again, so increments it hadn't seen still count.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PnCounter {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub incs: BTreeMap<u64, u64>, // node_id -> total added on that node
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub decs: BTreeMap<u64, u64>, // node_id -> total subtracted on that node
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deleted_incs: BTreeMap<u64, u64>, // node_id -> its total of adds the last delete saw
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deleted_decs: BTreeMap<u64, u64>, // node_id -> its total of subtractions the last delete saw
+}
+
+// what each node added to `totals` since the delete that saw `deleted`
+fn since_delete(totals: &BTreeMap<u64, u64>, deleted: &BTreeMap<u64, u64>) -> u64 {
+    totals.iter().map(|(node, &total)| total.saturating_sub(deleted.get(node).copied().unwrap_or(0))).sum()
+}
+
+impl PnCounter {
+    pub fn value(&self) -> i64 {
+        let incs = since_delete(&self.incs, &self.deleted_incs);
+        let decs = since_delete(&self.decs, &self.deleted_decs);
+        incs.wrapping_sub(decs) as i64
+    }
+
+    // the state deleting every add and subtraction this counter has seen
+    pub fn deleting(&self) -> PnCounter {
+        PnCounter { deleted_incs: self.incs.clone(), deleted_decs: self.decs.clone(), ..PnCounter::default() }
+    }
+
+    // whether a delete saw every add and subtraction
+    pub fn is_deleted(&self) -> bool {
+        since_delete(&self.incs, &self.deleted_incs) == 0 && since_delete(&self.decs, &self.deleted_decs) == 0
+    }
+
+    // the state raising `node_id`'s total of adds by `by`, or of subtractions if negative
+    pub fn bumped(&self, node_id: u64, by: i64) -> PnCounter {
+        let mut delta = PnCounter::default();
+        let (ours, bumped) = if by >= 0 { (&self.incs, &mut delta.incs) } else { (&self.decs, &mut delta.decs) };
+        bumped.insert(node_id, ours.get(&node_id).copied().unwrap_or(0) + by.unsigned_abs());
+        delta
+    }
+
+    pub fn merge(&mut self, other: &PnCounter) {
+        let pairs = [
+            (&mut self.incs, &other.incs),
+            (&mut self.decs, &other.decs),
+            (&mut self.deleted_incs, &other.deleted_incs),
+            (&mut self.deleted_decs, &other.deleted_decs),
+        ];
+        for (totals, theirs) in pairs {
+            for (&node, &total) in theirs {
+                let ours = totals.entry(node).or_default();
+                *ours = (*ours).max(total);
+            }
+        }
+    }
+}
+
+// Add-wins set. Every add is tagged with its dot, the (node_id, ts) of the write, and a remove
+// only removes the dots it saw, so an add the remove didn't know about survives it. Removed
+// dots are remembered to keep a late copy of their add from bringing the member back.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AwSet {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub adds: BTreeMap<String, BTreeSet<(u64, u64)>>, // member -> dots of its adds
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub removed: BTreeMap<String, BTreeSet<(u64, u64)>>, // member -> dots of adds removed since
+}
+
+impl AwSet {
+    // members with at least one add that wasn't removed, in order
+    pub fn members(&self) -> Vec<String> {
+        self.adds
+            .iter()
+            .filter(|(member, dots)| match self.removed.get(*member) {
+                Some(removed) => !dots.is_subset(removed),
+                None => !dots.is_empty(),
+            })
+            .map(|(member, _)| member.clone())
+            .collect()
+    }
+
+    // adding `members` with the dot (node_id, ts)
+    pub fn adding(members: &[String], node_id: u64, ts: u64) -> AwSet {
+        let adds = members.iter().map(|m| (m.clone(), BTreeSet::from([(node_id, ts)]))).collect();
+        AwSet { adds, removed: BTreeMap::new() }
+    }
+
+    // removing every add of `members` this set has seen
+    pub fn removing(&self, members: &[String]) -> AwSet {
+        let removed = members
+            .iter()
+            .filter_map(|m| Some((m.clone(), self.adds.get(m)?.clone())))
+            .collect();
+        AwSet { adds: BTreeMap::new(), removed }
+    }
+
+    // removing every add this set has seen; like any remove, it loses to concurrent adds
+    pub fn deleting(&self) -> AwSet {
+        AwSet { adds: BTreeMap::new(), removed: self.adds.clone() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.removed.is_empty()
+    }
+
+    fn merge(&mut self, other: &AwSet) {
+        for (ours, theirs) in [(&mut self.adds, &other.adds), (&mut self.removed, &other.removed)] {
+            for (member, dots) in theirs {
+                ours.entry(member.clone()).or_default().extend(dots);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Crdt {
+    Counter(PnCounter),
+    Set(AwSet),
+}
+
+impl Crdt {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Crdt::Counter(_) => "counter",
+            Crdt::Set(_) => "set",
+        }
+    }
+
+    // Joins `other` into this state; None if that changes nothing. The API keeps a key to one
+    // type, but should replicas still disagree a set replaces a counter, whichever came first.
+    pub fn merged(&self, other: &Crdt) -> Option<Crdt> {
+        let mut merged = self.clone();
+        match (&mut merged, other) {
+            (Crdt::Counter(ours), Crdt::Counter(theirs)) => ours.merge(theirs),
+            (Crdt::Set(ours), Crdt::Set(theirs)) => ours.merge(theirs),
+            (Crdt::Counter(_), Crdt::Set(_)) => merged = other.clone(),
+            (Crdt::Set(_), Crdt::Counter(_)) => {}
+        }
+        (merged != *self).then_some(merged)
+    }
+
+    // the state deleting everything this one has seen
+    pub fn deleting(&self) -> Crdt {
+        match self {
+            Crdt::Counter(counter) => Crdt::Counter(counter.deleting()),
+            Crdt::Set(set) => Crdt::Set(set.deleting()),
+        }
+    }
+
+    // Nothing is left that a delete didn't see: the key reads as deleted. A set whose members
+    // were all removed counts too, like a missing set reads as an empty one.
+    pub fn is_deleted(&self) -> bool {
+        match self {
+            Crdt::Counter(counter) => counter.is_deleted(),
+            Crdt::Set(set) => set.members().is_empty(),
+        }
+    }
+
+    // what plain reads of the key return: the count, or the members as a JSON array
+    pub fn render(&self) -> Vec<u8> {
+        match self {
+            Crdt::Counter(counter) => counter.value().to_string().into_bytes(),
+            Crdt::Set(set) => serde_json::to_vec(&set.members()).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(states: &[&Crdt]) -> Crdt {
+        let mut acc = states[0].clone();
+        for state in &states[1..] {
+            if let Some(merged) = acc.merged(state) {
+                acc = merged;
+            }
+        }
+        acc
+    }
+
+    #[test]
+    fn counter_merge_is_order_independent_and_idempotent() {
+        let base = PnCounter::default();
+        let a = Crdt::Counter(base.bumped(1, 5));
+        let b = Crdt::Counter(base.bumped(2, -2));
+        let c = Crdt::Counter(base.bumped(1, 5).bumped(1, 3));
+
+        let forward = joined(&[&a, &b, &c]);
+        assert_eq!(forward, joined(&[&c, &b, &a, &b]));
+        assert_eq!(forward.merged(&a), None);
+        let Crdt::Counter(counter) = forward else { panic!("not a counter") };
+        assert_eq!(counter.value(), 6);
+    }
+
+    #[test]
+    fn counter_delete_keeps_unseen_increments() {
+        let mut seen = PnCounter::default();
+        seen.merge(&seen.bumped(1, 4));
+        let delete = Crdt::Counter(seen.deleting());
+        let concurrent = Crdt::Counter(PnCounter::default().bumped(2, 3));
+
+        let deleted = joined(&[&Crdt::Counter(seen.clone()), &delete]);
+        assert!(deleted.is_deleted());
+        // a late copy of what the delete saw changes nothing
+        assert_eq!(deleted.merged(&Crdt::Counter(seen)), None);
+
+        for state in [joined(&[&deleted, &concurrent]), joined(&[&concurrent, &delete])] {
+            let Crdt::Counter(counter) = &state else { panic!("not a counter") };
+            assert!(!state.is_deleted());
+            assert_eq!(counter.value(), 3);
+        }
+    }
+
+    #[test]
+    fn set_add_wins_over_a_concurrent_remove() {
+        let first = AwSet::adding(&["a".to_string()], 1, 10);
+        let remove = Crdt::Set(first.removing(&["a".to_string()]));
+        let again = Crdt::Set(AwSet::adding(&["a".to_string()], 2, 11));
+
+        for state in [joined(&[&Crdt::Set(first.clone()), &remove, &again]), joined(&[&again, &remove, &Crdt::Set(first.clone())])] {
+            let Crdt::Set(set) = state else { panic!("not a set") };
+            assert_eq!(set.members(), vec!["a".to_string()]);
+        }
+    }
+
+    #[test]
+    fn set_delete_removes_what_it_saw() {
+        let seen = AwSet::adding(&["a".to_string(), "b".to_string()], 1, 10);
+        let deleted = joined(&[&Crdt::Set(seen.clone()), &Crdt::Set(seen.deleting())]);
+        assert!(deleted.is_deleted());
+        assert_eq!(deleted.merged(&Crdt::Set(seen)), None);
+
+        let revived = joined(&[&deleted, &Crdt::Set(AwSet::adding(&["c".to_string()], 2, 11))]);
+        assert!(!revived.is_deleted());
+        let Crdt::Set(set) = revived else { panic!("not a set") };
+        assert_eq!(set.members(), vec!["c".to_string()]);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod blob;
+pub mod crdt;
+pub mod time;
 pub mod types;
 
-pub use types::{Operation, LogEntry};
\ No newline at end of file
+pub use crdt::{Crdt, PnCounter, AwSet};
+pub use time::now_ms;
+pub use types::{Operation, LogEntry, VersionVector, MAX_WRITE_BYTES};
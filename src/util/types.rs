@@ -1,9 +1,43 @@
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
+use crate::util::{AwSet, PnCounter};
+
+// node_id -> highest ts from that node a write has seen; with --conflicts siblings every
+// version of a key from that node stamped at or below it is superseded by the write
+pub type VersionVector = BTreeMap<u64, u64>;
+
+// key and value bytes a single logged write, a batch's ops together, may carry; base64 and
+// JSON around them keep it well inside a WAL record
+pub const MAX_WRITE_BYTES: usize = 8 * 1024 * 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Operation {
-    Put { key: String, value: String },
-    Delete { key: String },
+    Put {
+        key: String,
+        #[serde(with = "crate::util::blob")]
+        value: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        // absolute deadline in unix ms, fixed by the node that accepted the write
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        // causal context the client read, only used with --conflicts siblings
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<VersionVector>,
+    },
+    Delete {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<VersionVector>,
+    },
+    // joins a counter's per-node totals into the key (see util::crdt)
+    Counter { key: String, delta: PnCounter },
+    // joins adds and removes into the key's add-wins set
+    Set { key: String, delta: AwSet },
+    // drops the tombstone with the entry's (ts, node_id) once every replica has it
+    Purge { key: String },
+    // puts and deletes on distinct keys, logged, replicated and applied as one unit
+    Batch { ops: Vec<Operation> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,4 +45,35 @@ pub struct LogEntry {
     pub ts: u64,
     pub node_id: u64, // used for tie-breaking in Lamport clocks
     pub operation: Operation,
-}
\ No newline at end of file
+}
+
+impl Operation {
+    // every key the operation touches, a batch's included
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Operation::Batch { ops } => ops.iter().filter_map(Operation::key).collect(),
+            op => op.key().into_iter().collect(),
+        }
+    }
+
+    // key and value bytes the operation carries, counted against MAX_WRITE_BYTES
+    pub fn size(&self) -> usize {
+        match self {
+            Operation::Put { key, value, .. } => key.len() + value.len(),
+            Operation::Batch { ops } => ops.iter().map(Operation::size).sum(),
+            op => op.key().map_or(0, str::len),
+        }
+    }
+
+    // the single key the operation touches; None for batches
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Operation::Put { key, .. } => Some(key),
+            Operation::Delete { key, .. } => Some(key),
+            Operation::Counter { key, .. } => Some(key),
+            Operation::Set { key, .. } => Some(key),
+            Operation::Purge { key } => Some(key),
+            Operation::Batch { .. } => None,
+        }
+    }
+}